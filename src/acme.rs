@@ -0,0 +1,216 @@
+use crate::{DnsProvider, NsResourceRecord, NsddnsError, RRType, Result};
+
+/// Label under which ACME DNS-01 challenges are published
+pub const CHALLENGE_LABEL: &str = "_acme-challenge";
+
+/// Namesilo reply code for a domain which is not active in the account
+const REPLY_DOMAIN_NOT_OWNED: u32 = 200;
+
+/// Name of the TXT record holding the challenge for a domain
+///
+/// Accepts the domain being validated (`example.com`, as certbot passes it) as well as the full
+/// challenge name (`_acme-challenge.example.com.`, as lego passes it).
+pub fn challenge_name(fqdn: &str) -> String {
+    let fqdn = fqdn.trim().trim_end_matches('.').to_ascii_lowercase();
+    // a wildcard certificate is validated through the name it covers
+    let fqdn = fqdn.strip_prefix("*.").unwrap_or(&fqdn);
+    if fqdn.starts_with(&format!("{}.", CHALLENGE_LABEL)) {
+        fqdn.to_owned()
+    } else {
+        format!("{}.{}", CHALLENGE_LABEL, fqdn)
+    }
+}
+
+/// Find the domain (zone) a challenge name belongs to, along with the domain's records
+///
+/// `domains` are tried first, then every parent of the name from the longest down to two
+/// labels, until the provider lists records for one of them.
+pub fn find_zone(
+    provider: &dyn DnsProvider,
+    domains: &[&str],
+    name: &str,
+) -> Result<(String, Vec<NsResourceRecord>)> {
+    let mut candidates: Vec<String> = domains
+        .iter()
+        .filter(|domain| name.ends_with(&format!(".{}", domain)))
+        .map(|domain| domain.to_string())
+        .collect();
+    let labels: Vec<&str> = name.split('.').collect();
+    for i in 1..labels.len().saturating_sub(1) {
+        let parent = labels[i..].join(".");
+        if !candidates.contains(&parent) {
+            candidates.push(parent);
+        }
+    }
+
+    for domain in candidates {
+        match provider.list_records(&domain) {
+            Ok(records) => return Ok((domain, records)),
+            // Namesilo refuses domains which are not in the account, any other error is real
+            Err(NsddnsError::Api { code, detail }) if code == REPLY_DOMAIN_NOT_OWNED => {
+                crate::debug!("{} is not a usable domain: {} ({})", domain, detail, code)
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Err(NsddnsError::Config(format!(
+        "no domain in the account contains {}",
+        name
+    )))
+}
+
+/// Publish a challenge value under `name`, unless it is already published
+///
+/// Other TXT records under the name are left alone, so the challenges of a certificate covering
+/// both `example.com` and `*.example.com` can be published side by side.
+pub fn present(
+    provider: &dyn DnsProvider,
+    domain: &str,
+    records: &[NsResourceRecord],
+    name: &str,
+    value: &str,
+) -> Result<()> {
+    if records
+        .iter()
+        .any(|rr| is_challenge(rr, name) && rr.record_value == value)
+    {
+        crate::info!("Challenge TXT record for {} already exists.", name);
+        return Ok(());
+    }
+
+    let record_id =
+        provider.create_record(domain, &NsResourceRecord::new(name, RRType::TXT, value))?;
+    crate::notice!(
+        { "DOMAIN" => domain, "HOST" => name, "RECORD_ID" => record_id },
+        "Challenge TXT record for {} created with ID {}",
+        name, record_id
+    );
+    Ok(())
+}
+
+/// Delete the challenge TXT records under `name`, only those holding `value` if one is given
+///
+/// Returns the number of records deleted.
+pub fn cleanup(
+    provider: &dyn DnsProvider,
+    domain: &str,
+    records: &[NsResourceRecord],
+    name: &str,
+    value: Option<&str>,
+) -> Result<usize> {
+    let mut deleted = 0;
+    for rr in records
+        .iter()
+        .filter(|rr| is_challenge(rr, name) && value.map_or(true, |v| rr.record_value == v))
+    {
+        provider.delete_record(domain, rr)?;
+        crate::notice!(
+            { "DOMAIN" => domain, "HOST" => name, "RECORD_ID" => rr.record_id },
+            "Challenge TXT record for {} with ID {} deleted",
+            name, rr.record_id
+        );
+        deleted += 1;
+    }
+    Ok(deleted)
+}
+
+/// Whether a record is a challenge TXT record under `name`
+fn is_challenge(rr: &NsResourceRecord, name: &str) -> bool {
+    rr.record_type == RRType::TXT && rr.record_host.eq_ignore_ascii_case(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mock::MockNamesilo, HttpClient, NamesiloProvider};
+
+    #[test]
+    fn test_challenge_name() {
+        assert_eq!(challenge_name("example.com"), "_acme-challenge.example.com");
+        assert_eq!(
+            challenge_name("*.Example.com"),
+            "_acme-challenge.example.com"
+        );
+        assert_eq!(
+            challenge_name("_acme-challenge.www.example.com."),
+            "_acme-challenge.www.example.com"
+        );
+    }
+
+    #[test]
+    fn test_present_and_cleanup() -> Result<()> {
+        let mock = MockNamesilo::start("test-key")?;
+        mock.add_domain("example.com");
+        mock.add_record("example.com", "www.example.com", "A", "1.2.3.4");
+        let provider = NamesiloProvider::new(
+            &mock.provider_config("test-key"),
+            &HttpClient::new(&Default::default())?,
+        );
+
+        let name = challenge_name("www.example.com");
+        let (domain, records) = find_zone(&provider, &[], &name)?;
+        assert_eq!(domain, "example.com");
+        present(&provider, &domain, &records, &name, "token-1")?;
+        present(&provider, &domain, &records, &name, "token-2")?;
+        let (_, records) = find_zone(&provider, &["example.com"], &name)?;
+        present(&provider, &domain, &records, &name, "token-1")?;
+        let challenges = || -> Vec<String> {
+            mock.records("example.com")
+                .into_iter()
+                .filter(|rr| rr.record_type == RRType::TXT)
+                .map(|rr| format!("{} {}", rr.record_host, rr.record_value))
+                .collect()
+        };
+        assert_eq!(
+            challenges(),
+            vec![
+                "_acme-challenge.www.example.com token-1",
+                "_acme-challenge.www.example.com token-2"
+            ]
+        );
+
+        let (_, records) = find_zone(&provider, &[], &name)?;
+        assert_eq!(
+            cleanup(&provider, &domain, &records, &name, Some("token-1"))?,
+            1
+        );
+        let (_, records) = find_zone(&provider, &[], &name)?;
+        assert_eq!(cleanup(&provider, &domain, &records, &name, None)?, 1);
+        assert!(challenges().is_empty());
+        assert_eq!(mock.records("example.com").len(), 1);
+
+        assert!(matches!(
+            find_zone(&provider, &[], "_acme-challenge.example.org"),
+            Err(NsddnsError::Config(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_zone_reports_api_errors() -> Result<()> {
+        let mock = MockNamesilo::start("test-key")?;
+        mock.add_domain("example.com");
+        let provider = NamesiloProvider::new(
+            &mock.provider_config("test-key"),
+            &HttpClient::new(&Default::default())?,
+        );
+        let fixture = |name: &str| {
+            let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures/namesilo")
+                .join(name);
+            std::fs::read_to_string(path).unwrap()
+        };
+
+        // a domain which is not in the account only means the next candidate is tried
+        mock.inject_body("dnsListRecords", &fixture("domain_not_owned.xml"));
+        let (domain, _) = find_zone(&provider, &[], "_acme-challenge.www.example.com")?;
+        assert_eq!(domain, "example.com");
+
+        mock.inject_body("dnsListRecords", &fixture("invalid_key.xml"));
+        assert!(matches!(
+            find_zone(&provider, &[], "_acme-challenge.www.example.com"),
+            Err(NsddnsError::Api { code: 110, detail }) if detail == "Invalid API Key"
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::rand::random_duration;
+
+/// How often a sleeping daemon checks whether it has been asked to stop
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Timing of the checks made by the long-running daemon mode
+pub struct Schedule {
+    /// Time between checks while everything succeeds
+    pub interval: Duration,
+    /// Upper bound of the random delay added to every wait
+    pub jitter: Duration,
+    /// Wait after the first failed check, doubled for every further consecutive failure
+    pub backoff: Duration,
+}
+
+impl Schedule {
+    /// Time to wait before the next check, given the number of consecutive failed checks
+    ///
+    /// Failed checks are retried sooner than the regular interval, backing off exponentially
+    /// until the wait reaches the interval again.
+    pub fn next_delay(&self, consecutive_failures: u32) -> Duration {
+        let base = if consecutive_failures == 0 {
+            self.interval
+        } else {
+            let factor = 1u32
+                .checked_shl(consecutive_failures - 1)
+                .unwrap_or(u32::MAX);
+            self.backoff.saturating_mul(factor).min(self.interval)
+        };
+
+        base.saturating_add(random_duration(self.jitter))
+    }
+}
+
+/// Sleep for `delay`, waking early if `stop` is set
+///
+/// Returns false if the sleep was cut short because the daemon should stop.
+pub fn sleep_unless_stopped(delay: Duration, stop: &AtomicBool) -> bool {
+    // measured from the start rather than against a deadline, which a huge delay would overflow
+    let start = Instant::now();
+    loop {
+        if stop.load(Ordering::Relaxed) {
+            return false;
+        }
+        let elapsed = start.elapsed();
+        if elapsed >= delay {
+            return true;
+        }
+        thread::sleep((delay - elapsed).min(STOP_POLL_INTERVAL));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_delay_backs_off_to_interval() {
+        let schedule = Schedule {
+            interval: Duration::from_secs(3600),
+            jitter: Duration::ZERO,
+            backoff: Duration::from_secs(30),
+        };
+
+        assert_eq!(schedule.next_delay(0), Duration::from_secs(3600));
+        assert_eq!(schedule.next_delay(1), Duration::from_secs(30));
+        assert_eq!(schedule.next_delay(2), Duration::from_secs(60));
+        assert_eq!(schedule.next_delay(4), Duration::from_secs(240));
+        assert_eq!(schedule.next_delay(8), Duration::from_secs(3600));
+        assert_eq!(schedule.next_delay(100), Duration::from_secs(3600));
+
+        let schedule = Schedule {
+            interval: Duration::MAX,
+            jitter: Duration::MAX,
+            backoff: Duration::MAX,
+        };
+        assert_eq!(schedule.next_delay(0), Duration::MAX);
+        assert_eq!(schedule.next_delay(3), Duration::MAX);
+    }
+
+    #[test]
+    fn test_next_delay_jitter_is_bounded() {
+        let schedule = Schedule {
+            interval: Duration::from_secs(60),
+            jitter: Duration::from_secs(10),
+            backoff: Duration::from_secs(5),
+        };
+
+        for _ in 0..100 {
+            let delay = schedule.next_delay(0);
+            assert!(delay >= Duration::from_secs(60));
+            assert!(delay <= Duration::from_secs(70));
+        }
+    }
+
+    #[test]
+    fn test_sleep_unless_stopped() {
+        let stop = AtomicBool::new(true);
+        assert!(!sleep_unless_stopped(Duration::MAX, &stop));
+
+        let stop = AtomicBool::new(false);
+        assert!(sleep_unless_stopped(Duration::from_millis(1), &stop));
+    }
+}
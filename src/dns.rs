@@ -0,0 +1,575 @@
+use std::{
+    fmt,
+    io::{Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{rand::random_u64, NsddnsError, RRType, Result};
+
+/// Authoritative nameservers of domains hosted with Namesilo
+pub const NAMESILO_NAMESERVERS: [&str; 3] = ["ns1.dnsowl.com", "ns2.dnsowl.com", "ns3.dnsowl.com"];
+
+/// Time a single DNS query may take
+const DNS_QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Largest DNS message accepted over UDP
+const DNS_UDP_MAX_SIZE: usize = 4096;
+
+/// DNS class IN
+const CLASS_IN: u16 = 1;
+
+/// Response code of a name which does not exist
+const RCODE_NXDOMAIN: u8 = 3;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// How updated records are checked against the authoritative nameservers
+pub struct VerifyConfig {
+    /// Nameservers to query, as host names or addresses with an optional port
+    pub nameservers: Vec<String>,
+    /// How long to wait for the new value to appear
+    pub timeout: Duration,
+    /// Time between polls of the nameservers
+    pub interval: Duration,
+}
+
+impl Default for VerifyConfig {
+    fn default() -> Self {
+        VerifyConfig {
+            nameservers: NAMESILO_NAMESERVERS.map(String::from).to_vec(),
+            timeout: Duration::from_secs(60),
+            interval: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Outcome of checking an updated record against the authoritative nameservers
+pub enum Verification {
+    /// Every nameserver which answered serves the new value
+    Verified,
+    /// The timeout expired before every nameserver served the new value, though some do, or none
+    /// could be reached
+    Pending,
+    /// The timeout expired and no nameserver serves the new value; holds the values they do serve
+    Mismatch(Vec<String>),
+}
+
+impl fmt::Display for Verification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Verification::Verified => f.write_str("verified"),
+            Verification::Pending => f.write_str("pending"),
+            Verification::Mismatch(_) => f.write_str("mismatch"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// A record served by a nameserver
+pub struct Answer {
+    /// Value, spelled the way Namesilo lists it
+    pub value: String,
+    /// TTL in seconds
+    pub ttl: u32,
+}
+
+/// DNS type code of a record type
+fn qtype(record_type: RRType) -> u16 {
+    match record_type {
+        RRType::A => 1,
+        RRType::AAAA => 28,
+        RRType::CNAME => 5,
+        RRType::MX => 15,
+        RRType::TXT => 16,
+        RRType::SRV => 33,
+        RRType::CAA => 257,
+    }
+}
+
+/// Resolve a nameserver given as `host`, `host:port`, `ip` or `ip:port` (port 53 by default)
+pub fn nameserver_addrs(nameserver: &str) -> Result<Vec<SocketAddr>> {
+    if let Ok(addr) = nameserver.parse::<SocketAddr>() {
+        return Ok(vec![addr]);
+    }
+    if let Ok(ip) = nameserver.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, 53)]);
+    }
+
+    let addrs = if nameserver.contains(':') {
+        nameserver.to_socket_addrs()
+    } else {
+        (nameserver, 53).to_socket_addrs()
+    };
+    addrs
+        .map(|addrs| addrs.collect())
+        .map_err(|e| NsddnsError::io(format!("Failed to resolve nameserver {}", nameserver), e))
+}
+
+/// Ask a nameserver for a host's records
+///
+/// Queries go over UDP, and are repeated over TCP if the answer was truncated. A host without
+/// records of the type yields an empty list.
+pub fn query(server: SocketAddr, host: &str, record_type: RRType) -> Result<Vec<Answer>> {
+    let id = random_u64() as u16;
+    let request = build_query(id, host, qtype(record_type))?;
+    let io_error = |e| NsddnsError::io(format!("DNS query to {} failed", server), e);
+
+    let bind_addr: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind_addr).map_err(io_error)?;
+    socket
+        .set_read_timeout(Some(DNS_QUERY_TIMEOUT))
+        .map_err(io_error)?;
+    socket.connect(server).map_err(io_error)?;
+    socket.send(&request).map_err(io_error)?;
+
+    let mut response = vec![0; DNS_UDP_MAX_SIZE];
+    let len = socket.recv(&mut response).map_err(io_error)?;
+    response.truncate(len);
+    match parse_response(id, &response, record_type) {
+        Err(DnsError::Truncated) => {}
+        result => return result.map_err(NsddnsError::from),
+    }
+
+    // the answer did not fit into a datagram, TCP has no such limit
+    let mut stream = TcpStream::connect_timeout(&server, DNS_QUERY_TIMEOUT).map_err(io_error)?;
+    stream
+        .set_read_timeout(Some(DNS_QUERY_TIMEOUT))
+        .map_err(io_error)?;
+    let mut framed = (request.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(&request);
+    stream.write_all(&framed).map_err(io_error)?;
+
+    let mut len = [0; 2];
+    stream.read_exact(&mut len).map_err(io_error)?;
+    let mut response = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut response).map_err(io_error)?;
+    Ok(parse_response(id, &response, record_type)?)
+}
+
+/// Ask every nameserver for a host's records, skipping those which fail
+///
+/// Returns one list of records per nameserver which answered.
+pub fn query_nameservers(
+    nameservers: &[String],
+    host: &str,
+    record_type: RRType,
+) -> Vec<Vec<Answer>> {
+    let mut answers = Vec::new();
+    for nameserver in nameservers {
+        let addrs = match nameserver_addrs(nameserver) {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                crate::debug!("{}", e);
+                continue;
+            }
+        };
+        // one answer per nameserver, from the first of its addresses which responds
+        for addr in addrs {
+            match query(addr, host, record_type) {
+                Ok(records) => {
+                    let values: Vec<&str> = records.iter().map(|r| r.value.as_str()).collect();
+                    crate::debug!(
+                        "{} ({}) serves {} {} as [{}]",
+                        nameserver,
+                        addr,
+                        record_type,
+                        host,
+                        values.join(", ")
+                    );
+                    answers.push(records);
+                    break;
+                }
+                Err(e) => crate::debug!("{}", e),
+            }
+        }
+    }
+    answers
+}
+
+/// Whether the nameservers serve exactly `expected` for a host, with `ttl` if one is given
+///
+/// At least one nameserver has to answer, and every one which does has to serve `expected` and
+/// nothing else. Any doubt means the provider has to be asked.
+pub fn serves_value(
+    nameservers: &[String],
+    host: &str,
+    record_type: RRType,
+    expected: &str,
+    ttl: Option<u32>,
+) -> bool {
+    let answers = query_nameservers(nameservers, host, record_type);
+    !answers.is_empty()
+        && answers.iter().all(|records| {
+            records.len() == 1
+                && values_match(&records[0].value, expected)
+                && ttl.map_or(true, |ttl| records[0].ttl == ttl)
+        })
+}
+
+/// Poll the nameservers until all of them serve `expected` for a host, or the timeout expires
+pub fn verify_record(
+    config: &VerifyConfig,
+    host: &str,
+    record_type: RRType,
+    expected: &str,
+) -> Verification {
+    let start = Instant::now();
+    loop {
+        let answers = query_nameservers(&config.nameservers, host, record_type);
+        let serving = answers
+            .iter()
+            .filter(|records| records.iter().any(|r| values_match(&r.value, expected)))
+            .count();
+        if !answers.is_empty() && serving == answers.len() {
+            return Verification::Verified;
+        }
+
+        if start.elapsed().saturating_add(config.interval) > config.timeout {
+            if serving > 0 || answers.is_empty() {
+                return Verification::Pending;
+            }
+            let mut served: Vec<String> = answers.into_iter().flatten().map(|r| r.value).collect();
+            served.sort();
+            served.dedup();
+            return Verification::Mismatch(served);
+        }
+        thread::sleep(config.interval);
+    }
+}
+
+/// Whether two record values are the same, comparing addresses by value (`::1` == `0::1`)
+fn values_match(a: &str, b: &str) -> bool {
+    match (a.parse::<IpAddr>(), b.parse::<IpAddr>()) {
+        (Ok(a), Ok(b)) => a == b,
+        // TXT values such as ACME tokens are case-sensitive
+        _ => a == b,
+    }
+}
+
+/// Ways a DNS response can be unusable
+#[derive(Debug, PartialEq, Eq)]
+enum DnsError {
+    /// The answer was cut short and has to be asked for over TCP
+    Truncated,
+    /// The response is malformed or reports an error
+    Invalid(String),
+}
+
+impl From<DnsError> for NsddnsError {
+    fn from(e: DnsError) -> Self {
+        match e {
+            DnsError::Truncated => NsddnsError::Parse(String::from("truncated DNS response")),
+            DnsError::Invalid(msg) => NsddnsError::Parse(format!("invalid DNS response: {}", msg)),
+        }
+    }
+}
+
+/// Build a non-recursive query for a host's records
+fn build_query(id: u16, host: &str, qtype: u16) -> Result<Vec<u8>> {
+    let mut message = Vec::with_capacity(512);
+    message.extend_from_slice(&id.to_be_bytes());
+    // flags: standard query, no recursion wanted from an authoritative server
+    message.extend_from_slice(&[0, 0]);
+    // one question, no answer, authority or additional records
+    message.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(NsddnsError::Parse(format!(
+                "invalid host name for a DNS query: {}",
+                host
+            )));
+        }
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+    message.extend_from_slice(&qtype.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(message)
+}
+
+/// Read the records of a type from a response
+fn parse_response(
+    id: u16,
+    response: &[u8],
+    record_type: RRType,
+) -> std::result::Result<Vec<Answer>, DnsError> {
+    let invalid = |msg: &str| DnsError::Invalid(msg.to_owned());
+    if response.len() < 12 {
+        return Err(invalid("shorter than a header"));
+    }
+    let u16_at = |pos: usize| -> std::result::Result<u16, DnsError> {
+        response
+            .get(pos..pos + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .ok_or_else(|| invalid("cut off"))
+    };
+
+    if u16_at(0)? != id {
+        return Err(invalid("ID does not match the query"));
+    }
+    let flags = u16_at(2)?;
+    if flags & 0x8000 == 0 {
+        return Err(invalid("not a response"));
+    }
+    if flags & 0x0200 != 0 {
+        return Err(DnsError::Truncated);
+    }
+    match (flags & 0x000f) as u8 {
+        0 => {}
+        RCODE_NXDOMAIN => return Ok(Vec::new()),
+        rcode => return Err(DnsError::Invalid(format!("response code {}", rcode))),
+    }
+
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(response, pos)? + 4;
+    }
+
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        pos = skip_name(response, pos)?;
+        let rtype = u16_at(pos)?;
+        let ttl = (u16_at(pos + 4)? as u32) << 16 | u16_at(pos + 6)? as u32;
+        let rdlength = u16_at(pos + 8)? as usize;
+        let rdata_pos = pos + 10;
+        let rdata = response
+            .get(rdata_pos..rdata_pos + rdlength)
+            .ok_or_else(|| invalid("cut off"))?;
+        pos = rdata_pos + rdlength;
+
+        // CNAMEs and other records in the answer are not what was asked for
+        if rtype != qtype(record_type) {
+            continue;
+        }
+        let bad_record = || DnsError::Invalid(format!("bad {} record", record_type));
+        // values are spelled the way Namesilo lists them, priorities are left out
+        let value = match (record_type, rdata.len()) {
+            (RRType::A, 4) => Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]).to_string(),
+            (RRType::AAAA, 16) => {
+                let octets: [u8; 16] = rdata.try_into().map_err(|_| bad_record())?;
+                Ipv6Addr::from(octets).to_string()
+            }
+            (RRType::CNAME, _) => read_name(response, rdata_pos)?,
+            (RRType::MX, 3..) => read_name(response, rdata_pos + 2)?,
+            (RRType::TXT, _) => parse_txt(rdata)?,
+            (RRType::SRV, 7..) => format!(
+                "{} {} {}",
+                u16_at(rdata_pos + 2)?,
+                u16_at(rdata_pos + 4)?,
+                read_name(response, rdata_pos + 6)?
+            ),
+            (RRType::CAA, 2..) => {
+                let tag = rdata.get(2..2 + rdata[1] as usize).ok_or_else(bad_record)?;
+                format!(
+                    "{} {} \"{}\"",
+                    rdata[0],
+                    String::from_utf8_lossy(tag),
+                    String::from_utf8_lossy(&rdata[2 + tag.len()..])
+                )
+            }
+            _ => return Err(bad_record()),
+        };
+        records.push(Answer { value, ttl });
+    }
+    Ok(records)
+}
+
+/// Value of a TXT record, joining its character strings the way providers show them
+fn parse_txt(mut rdata: &[u8]) -> std::result::Result<String, DnsError> {
+    let mut value = Vec::new();
+    while let Some((&len, rest)) = rdata.split_first() {
+        let string = rest
+            .get(..len as usize)
+            .ok_or_else(|| DnsError::Invalid(String::from("bad TXT record")))?;
+        value.extend_from_slice(string);
+        rdata = &rest[len as usize..];
+    }
+    Ok(String::from_utf8_lossy(&value).into_owned())
+}
+
+/// Read a (possibly compressed) name starting at `pos`, without the trailing dot
+fn read_name(message: &[u8], mut pos: usize) -> std::result::Result<String, DnsError> {
+    let cut_off = || DnsError::Invalid(String::from("cut off"));
+    let mut labels = Vec::new();
+    // every pointer has to go backwards, which rules out loops
+    let mut limit = pos;
+    loop {
+        let len = *message.get(pos).ok_or_else(cut_off)?;
+        match len {
+            0 => return Ok(labels.join(".")),
+            len if len & 0xc0 == 0xc0 => {
+                let low = *message.get(pos + 1).ok_or_else(cut_off)?;
+                let target = ((len as usize & 0x3f) << 8) | low as usize;
+                if target >= limit {
+                    return Err(DnsError::Invalid(String::from("bad name pointer")));
+                }
+                pos = target;
+                limit = target;
+            }
+            len => {
+                let label = message
+                    .get(pos + 1..pos + 1 + len as usize)
+                    .ok_or_else(cut_off)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                pos += 1 + len as usize;
+            }
+        }
+    }
+}
+
+/// Position right after a (possibly compressed) name starting at `pos`
+fn skip_name(message: &[u8], mut pos: usize) -> std::result::Result<usize, DnsError> {
+    loop {
+        let len = *message
+            .get(pos)
+            .ok_or_else(|| DnsError::Invalid(String::from("cut off")))?;
+        match len {
+            0 => return Ok(pos + 1),
+            // a pointer to an earlier name ends this one
+            len if len & 0xc0 == 0xc0 => return Ok(pos + 2),
+            len => pos += 1 + len as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answer a query with the given records of its type (as rdata)
+    fn answer(query: &[u8], rdatas: &[&[u8]], flags: u16) -> Vec<u8> {
+        let mut response = query.to_vec();
+        response[2..4].copy_from_slice(&(0x8400 | flags).to_be_bytes());
+        response[6..8].copy_from_slice(&(rdatas.len() as u16).to_be_bytes());
+        let qtype = &query[query.len() - 4..query.len() - 2];
+        for rdata in rdatas {
+            // name pointer to the question, type, class, ttl, rdata
+            response.extend_from_slice(&[0xc0, 12]);
+            response.extend_from_slice(qtype);
+            response.extend_from_slice(&[0, 1, 0, 0, 0x0e, 0x10]);
+            response.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            response.extend_from_slice(rdata);
+        }
+        response
+    }
+
+    /// Records with the given values and the TTL `answer` gives them
+    fn served(values: &[&str]) -> Vec<Answer> {
+        values
+            .iter()
+            .map(|v| Answer {
+                value: v.to_string(),
+                ttl: 3600,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let query = build_query(0x1234, "www.example.com", 1).unwrap();
+        assert_eq!(&query[12..29], b"\x03www\x07example\x03com\x00");
+
+        let response = answer(&query, &[&[203, 0, 113, 7], &[203, 0, 113, 8]], 0);
+        assert_eq!(
+            parse_response(0x1234, &response, RRType::A),
+            Ok(served(&["203.0.113.7", "203.0.113.8"]))
+        );
+        assert!(parse_response(0x4321, &response, RRType::A).is_err());
+        assert!(parse_response(0x1234, &response[..response.len() - 2], RRType::A).is_err());
+        assert_eq!(
+            parse_response(0x1234, &answer(&query, &[], 0x0200), RRType::A),
+            Err(DnsError::Truncated)
+        );
+        assert_eq!(
+            parse_response(
+                0x1234,
+                &answer(&query, &[], RCODE_NXDOMAIN as u16),
+                RRType::A
+            ),
+            Ok(Vec::new())
+        );
+
+        // MX exchange compressed against the question's name
+        let query = build_query(0x1234, "example.com", 15).unwrap();
+        let response = answer(&query, &[b"\x00\x0a\x03mx1\xc0\x0c"], 0);
+        assert_eq!(
+            parse_response(0x1234, &response, RRType::MX),
+            Ok(served(&["mx1.example.com"]))
+        );
+        let query = build_query(0x1234, "example.com", 257).unwrap();
+        let response = answer(&query, &[b"\x00\x05issueletsencrypt.org"], 0);
+        assert_eq!(
+            parse_response(0x1234, &response, RRType::CAA),
+            Ok(served(&["0 issue \"letsencrypt.org\""]))
+        );
+
+        let query = build_query(0x1234, "_acme-challenge.example.com", 16).unwrap();
+        let response = answer(&query, &[b"\x05gfxJk\x04-Q_z"], 0);
+        assert_eq!(
+            parse_response(0x1234, &response, RRType::TXT),
+            Ok(served(&["gfxJk-Q_z"]))
+        );
+    }
+
+    #[test]
+    fn test_verify_against_local_server() -> Result<()> {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let responder = thread::spawn(move || {
+            let mut query = [0; 512];
+            for _ in 0..5 {
+                let (len, peer) = server.recv_from(&mut query).unwrap();
+                let ip = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+                server
+                    .send_to(&answer(&query[..len], &[&ip], 0), peer)
+                    .unwrap();
+            }
+        });
+
+        let config = VerifyConfig {
+            nameservers: vec![addr.to_string()],
+            timeout: Duration::ZERO,
+            interval: Duration::ZERO,
+        };
+        assert_eq!(
+            verify_record(&config, "www.example.com", RRType::AAAA, "2001:db8:0::1"),
+            Verification::Verified
+        );
+        assert_eq!(
+            verify_record(&config, "www.example.com", RRType::AAAA, "2001:db8::2"),
+            Verification::Mismatch(vec![String::from("2001:db8::1")])
+        );
+        assert!(serves_value(
+            &config.nameservers,
+            "www.example.com",
+            RRType::AAAA,
+            "2001:db8::1",
+            Some(3600)
+        ));
+        assert!(!serves_value(
+            &config.nameservers,
+            "www.example.com",
+            RRType::AAAA,
+            "2001:db8::2",
+            None
+        ));
+        assert!(!serves_value(
+            &config.nameservers,
+            "www.example.com",
+            RRType::AAAA,
+            "2001:db8::1",
+            Some(600)
+        ));
+        responder.join().unwrap();
+        Ok(())
+    }
+}
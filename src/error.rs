@@ -0,0 +1,103 @@
+use std::{error::Error, fmt, io};
+
+use crate::{redact::redact_url, RRType};
+
+/// Result type returned by the nsddns library
+pub type Result<T, E = NsddnsError> = std::result::Result<T, E>;
+
+#[derive(Debug)]
+/// Errors returned by the nsddns library
+pub enum NsddnsError {
+    /// The configuration is unreadable, malformed or incomplete
+    Config(String),
+    /// A local file could not be read or written
+    Io {
+        /// What was being done when the error happened
+        context: String,
+        /// Underlying I/O error
+        source: io::Error,
+    },
+    /// An HTTP request could not be completed (connection, TLS, timeout, HTTP status, ...)
+    ///
+    /// Credentials are removed from the request URL the error carries.
+    Transport(reqwest::Error),
+    /// Namesilo answered, but with a reply code other than success
+    Api {
+        /// Namesilo reply code
+        code: u32,
+        /// Namesilo's explanation of the code
+        detail: String,
+    },
+    /// A response could not be parsed
+    Parse(String),
+    /// The provider has no record for the host and type
+    RecordNotFound {
+        /// Fully qualified host of the record
+        host: String,
+        /// Type of the record
+        record_type: RRType,
+        /// Domain which was searched
+        domain: String,
+    },
+    /// The public IP address could not be determined
+    IpLookup(String),
+}
+
+impl NsddnsError {
+    /// Wrap an I/O error with a description of what was being done
+    pub(crate) fn io(context: impl Into<String>, source: io::Error) -> Self {
+        NsddnsError::Io {
+            context: context.into(),
+            source,
+        }
+    }
+}
+
+impl fmt::Display for NsddnsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NsddnsError::Config(msg) => f.write_str(msg),
+            NsddnsError::Io { context, source } => write!(f, "{}: {}", context, source),
+            NsddnsError::Transport(e) => write!(f, "HTTP request failed: {}", e),
+            NsddnsError::Api { code, detail } => {
+                write!(f, "Namesilo API returned code {}: {}", code, detail)
+            }
+            NsddnsError::Parse(msg) => write!(f, "failed to parse response: {}", msg),
+            NsddnsError::RecordNotFound {
+                host,
+                record_type,
+                domain,
+            } => write!(
+                f,
+                "No matching {} host record for '{}' in apex domain '{}'",
+                record_type, host, domain
+            ),
+            NsddnsError::IpLookup(msg) => write!(f, "failed to determine public IP: {}", msg),
+        }
+    }
+}
+
+impl Error for NsddnsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            NsddnsError::Io { source, .. } => Some(source),
+            NsddnsError::Transport(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for NsddnsError {
+    fn from(mut e: reqwest::Error) -> Self {
+        if let Some(url) = e.url_mut() {
+            redact_url(url);
+        }
+        NsddnsError::Transport(e)
+    }
+}
+
+impl From<roxmltree::Error> for NsddnsError {
+    fn from(e: roxmltree::Error) -> Self {
+        NsddnsError::Parse(e.to_string())
+    }
+}
@@ -0,0 +1,191 @@
+use std::{thread, time::Duration};
+
+use reqwest::{
+    blocking::{Client, Request, RequestBuilder, Response},
+    StatusCode,
+};
+
+use crate::{rand::random_duration, redact::redact_url, NsddnsError, Result};
+
+/// User agent sent with every request
+pub const DEFAULT_USER_AGENT: &str = concat!("nsddns/", env!("CARGO_PKG_VERSION"));
+
+/// Time a single request may take, from connecting to reading the whole response
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time allowed for establishing a connection
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of times a failed request is sent again
+pub const DEFAULT_RETRIES: u32 = 2;
+
+/// Wait before the first retry, doubled for every further one
+pub const DEFAULT_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Settings of the HTTP client used for IP lookups and provider APIs
+pub struct HttpConfig {
+    /// Time a single request may take, from connecting to reading the whole response
+    pub timeout: Duration,
+    /// Time allowed for establishing a connection
+    pub connect_timeout: Duration,
+    /// Number of times a failed request is sent again
+    pub retries: u32,
+    /// Wait before the first retry, doubled for every further one, plus up to as much jitter
+    pub retry_backoff: Duration,
+    /// User agent sent with every request
+    pub user_agent: String,
+    /// Proxy for all requests, instead of the one from `$HTTPS_PROXY`/`$HTTP_PROXY`
+    pub proxy: Option<String>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            timeout: DEFAULT_TIMEOUT,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+            retry_backoff: DEFAULT_RETRY_BACKOFF,
+            user_agent: DEFAULT_USER_AGENT.to_owned(),
+            proxy: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Which failed requests may be sent again
+pub enum RetryPolicy {
+    /// Reads, which are retried after any transient failure: connection errors, timeouts,
+    /// HTTP 429 and 5xx
+    Idempotent,
+    /// Changes, which are only retried if the connection failed, so the request never reached
+    /// the server and cannot have been applied
+    NotIdempotent,
+}
+
+#[derive(Clone, Debug)]
+/// HTTP client shared by the IP lookups and the providers
+///
+/// Cloning is cheap, clones share the same connection pool.
+pub struct HttpClient {
+    client: Client,
+    retries: u32,
+    retry_backoff: Duration,
+}
+
+impl HttpClient {
+    /// Build a client with the given settings
+    pub fn new(config: &HttpConfig) -> Result<HttpClient> {
+        let mut builder = Client::builder()
+            .timeout(config.timeout)
+            .connect_timeout(config.connect_timeout)
+            .user_agent(config.user_agent.as_str());
+        if let Some(proxy) = &config.proxy {
+            let proxy = reqwest::Proxy::all(proxy.as_str())
+                .map_err(|e| NsddnsError::Config(format!("invalid proxy URL {}: {}", proxy, e)))?;
+            builder = builder.proxy(proxy);
+        }
+
+        Ok(HttpClient {
+            client: builder.build()?,
+            retries: config.retries,
+            retry_backoff: config.retry_backoff,
+        })
+    }
+
+    /// Start a GET request
+    pub fn get(&self, url: &str) -> RequestBuilder {
+        self.client.get(url)
+    }
+
+    /// Send a request built with `get`, retrying transient failures the policy allows
+    ///
+    /// Once the retries are used up, the last error (or the last response, whatever its status)
+    /// is returned.
+    pub fn execute(&self, request: Request, policy: RetryPolicy) -> Result<Response> {
+        let mut attempt = 0;
+        loop {
+            // requests without a body can always be cloned
+            let retry_request = if attempt < self.retries {
+                request.try_clone()
+            } else {
+                None
+            };
+            let Some(retry_request) = retry_request else {
+                return Ok(self.client.execute(request)?);
+            };
+
+            let failure = match self.client.execute(retry_request) {
+                Ok(response) if is_transient_status(response.status()) => {
+                    if policy == RetryPolicy::NotIdempotent {
+                        return Ok(response);
+                    }
+                    format!("HTTP status {}", response.status())
+                }
+                Ok(response) => return Ok(response),
+                Err(e)
+                    if e.is_connect() || (policy == RetryPolicy::Idempotent && e.is_timeout()) =>
+                {
+                    NsddnsError::from(e).to_string()
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            let delay = self.retry_delay(attempt);
+            attempt += 1;
+            let mut logged_url = request.url().clone();
+            redact_url(&mut logged_url);
+            crate::warn!(
+                "request to {} failed ({}), retry {}/{} in {} ms",
+                logged_url,
+                failure,
+                attempt,
+                self.retries,
+                delay.as_millis()
+            );
+            thread::sleep(delay);
+        }
+    }
+
+    /// Wait before retrying after `attempt` earlier retries: exponential backoff plus jitter
+    fn retry_delay(&self, attempt: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempt).unwrap_or(u32::MAX);
+        self.retry_backoff
+            .saturating_mul(factor)
+            .saturating_add(random_duration(self.retry_backoff))
+    }
+}
+
+/// Whether a response status is worth retrying
+fn is_transient_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_delay() -> Result<()> {
+        let client = HttpClient::new(&HttpConfig {
+            retry_backoff: Duration::from_millis(100),
+            ..Default::default()
+        })?;
+
+        for (attempt, base) in [(0, 100), (1, 200), (3, 800)] {
+            let delay = client.retry_delay(attempt);
+            assert!(delay >= Duration::from_millis(base));
+            assert!(delay <= Duration::from_millis(base + 100));
+        }
+
+        let client = HttpClient::new(&HttpConfig {
+            retry_backoff: Duration::from_secs(u64::MAX),
+            ..Default::default()
+        })?;
+        assert_eq!(client.retry_delay(3), Duration::MAX);
+        assert!(is_transient_status(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(is_transient_status(StatusCode::TOO_MANY_REQUESTS));
+        assert!(!is_transient_status(StatusCode::NOT_FOUND));
+        Ok(())
+    }
+}
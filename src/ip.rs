@@ -0,0 +1,472 @@
+use std::{
+    ffi::CStr,
+    fmt, fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use crate::{http::RetryPolicy, HttpClient, NsddnsError, RRType, Result};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// How to find the IP address in the body of an IP discovery response
+pub enum IpFormat {
+    /// The whole (trimmed) body is the address
+    Text,
+    /// The body is a JSON object holding the address under a key
+    Json(String),
+    /// The body has `key=value` lines, one of which holds the address (e.g. Cloudflare's trace)
+    KeyValue(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Where an IP discovery source gets the address from
+pub enum IpSourceKind {
+    /// HTTP service which reports the public IP address of the caller
+    Http {
+        /// URL to fetch
+        url: String,
+        /// How to find the address in the response body
+        format: IpFormat,
+    },
+    /// Local network interface which holds the public address directly
+    Interface(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Source of the public IP address of this host
+pub struct IpSource {
+    /// Record type the reported address is used for (A for IPv4, AAAA for IPv6)
+    pub record_type: RRType,
+    /// Where the address comes from
+    pub kind: IpSourceKind,
+}
+
+impl IpSource {
+    /// HTTP source which answers with nothing but the address
+    pub fn text(url: &str, record_type: RRType) -> Self {
+        IpSource {
+            record_type,
+            kind: IpSourceKind::Http {
+                url: url.to_owned(),
+                format: IpFormat::Text,
+            },
+        }
+    }
+
+    /// Source which reads the address of a local network interface
+    pub fn interface(name: &str, record_type: RRType) -> Self {
+        IpSource {
+            record_type,
+            kind: IpSourceKind::Interface(name.to_owned()),
+        }
+    }
+}
+
+impl fmt::Display for IpSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            IpSourceKind::Http { url, .. } => f.write_str(url),
+            IpSourceKind::Interface(name) => write!(f, "interface {}", name),
+        }
+    }
+}
+
+/// IP discovery sources used when the config does not list any
+pub fn default_ip_sources() -> Vec<IpSource> {
+    vec![
+        // api.ipify.org only answers over IPv4, api6.ipify.org only over IPv6
+        IpSource::text("https://api.ipify.org", RRType::A),
+        IpSource::text("https://api6.ipify.org", RRType::AAAA),
+    ]
+}
+
+/// Whether an address could plausibly be the public address of this host
+pub fn is_public_ip(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                // carrier-grade NAT addresses (100.64.0.0/10)
+                || (ip.octets()[0] == 100 && (ip.octets()[1] & 0xc0) == 64)
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast())
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                // unique local addresses (fc00::/7)
+                || (segments[0] & 0xfe00) == 0xfc00
+                // link-local addresses (fe80::/10)
+                || (segments[0] & 0xffc0) == 0xfe80
+                // documentation addresses (2001:db8::/32)
+                || (segments[0] == 0x2001 && segments[1] == 0x0db8))
+        }
+    }
+}
+
+/// Validate that a reported address is a public address of the family a record type holds
+pub fn validate_ip(value: &str, record_type: RRType) -> Result<IpAddr> {
+    let ip: IpAddr = value
+        .trim()
+        .parse()
+        .map_err(|_| NsddnsError::Parse(format!("'{}' is not an IP address", value.trim())))?;
+
+    match (record_type, ip) {
+        (RRType::A, IpAddr::V4(_)) | (RRType::AAAA, IpAddr::V6(_)) => {}
+        _ => {
+            return Err(NsddnsError::Parse(format!(
+                "{} is not a valid address for {} records",
+                ip, record_type
+            )))
+        }
+    }
+    if !is_public_ip(&ip) {
+        return Err(NsddnsError::Parse(format!(
+            "{} is not a public address",
+            ip
+        )));
+    }
+
+    Ok(ip)
+}
+
+/// Extract the address from the body of an IP discovery response
+fn parse_ip_response(body: &str, format: &IpFormat) -> Result<String> {
+    match format {
+        IpFormat::Text => Ok(body.trim().to_owned()),
+        IpFormat::Json(key) => {
+            let response_json = json::parse(body)
+                .map_err(|e| NsddnsError::Parse(format!("response is not valid JSON: {}", e)))?;
+            response_json[key.as_str()]
+                .as_str()
+                .map(|ip| ip.to_owned())
+                .ok_or_else(|| {
+                    NsddnsError::Parse(format!("response JSON has no string key '{}'", key))
+                })
+        }
+        IpFormat::KeyValue(key) => body
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .find(|(k, _)| k.trim() == key)
+            .map(|(_, ip)| ip.trim().to_owned())
+            .ok_or_else(|| NsddnsError::Parse(format!("response has no line '{}=...'", key))),
+    }
+}
+
+// flags of IPv6 addresses which should never be published (see linux/if_addr.h)
+const IFA_F_TEMPORARY: u32 = 0x01;
+const IFA_F_DADFAILED: u32 = 0x08;
+const IFA_F_DEPRECATED: u32 = 0x20;
+const IFA_F_TENTATIVE: u32 = 0x40;
+const IFA_F_UNPUBLISHABLE: u32 =
+    IFA_F_TEMPORARY | IFA_F_DADFAILED | IFA_F_DEPRECATED | IFA_F_TENTATIVE;
+
+/// Parse the IPv6 addresses of an interface and their flags out of /proc/net/if_inet6
+///
+/// Each line holds the address, interface index, prefix length, scope, flags and interface name.
+fn parse_if_inet6(contents: &str, interface: &str) -> Vec<(Ipv6Addr, u32)> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 6 || fields[5] != interface {
+                return None;
+            }
+            let addr = u128::from_str_radix(fields[0], 16).ok()?;
+            let flags = u32::from_str_radix(fields[4], 16).ok()?;
+            Some((Ipv6Addr::from(addr), flags))
+        })
+        .collect()
+}
+
+/// All addresses assigned to a network interface, in the order the OS reports them
+fn interface_addresses(interface: &str) -> Result<Vec<IpAddr>> {
+    let mut ifaddrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    // SAFETY: getifaddrs only writes the list head, which is freed below
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return Err(NsddnsError::io(
+            "getifaddrs failed",
+            std::io::Error::last_os_error(),
+        ));
+    }
+
+    let mut addresses = Vec::new();
+    let mut found = false;
+    let mut cursor = ifaddrs;
+    while !cursor.is_null() {
+        // SAFETY: cursor is a non-null node of the list returned by getifaddrs
+        let ifaddr = unsafe { &*cursor };
+        cursor = ifaddr.ifa_next;
+
+        // SAFETY: ifa_name is a valid NUL terminated string for every node
+        let name = unsafe { CStr::from_ptr(ifaddr.ifa_name) };
+        if name.to_bytes() != interface.as_bytes() {
+            continue;
+        }
+        found = true;
+        if ifaddr.ifa_addr.is_null() {
+            continue;
+        }
+
+        // SAFETY: ifa_addr is non-null, and sa_family tells which sockaddr type it points to
+        match unsafe { (*ifaddr.ifa_addr).sa_family } as i32 {
+            libc::AF_INET => {
+                let sockaddr = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in) };
+                addresses.push(IpAddr::V4(Ipv4Addr::from(u32::from_be(
+                    sockaddr.sin_addr.s_addr,
+                ))));
+            }
+            libc::AF_INET6 => {
+                let sockaddr = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in6) };
+                addresses.push(IpAddr::V6(Ipv6Addr::from(sockaddr.sin6_addr.s6_addr)));
+            }
+            _ => {}
+        }
+    }
+    // SAFETY: ifaddrs came from getifaddrs and no references into it outlive this call
+    unsafe { libc::freeifaddrs(ifaddrs) };
+
+    if !found {
+        return Err(NsddnsError::IpLookup(format!(
+            "no network interface named {}",
+            interface
+        )));
+    }
+    Ok(addresses)
+}
+
+/// Read the public address of a record type's family from a local network interface
+///
+/// Private, carrier-grade NAT, link-local and ULA addresses are skipped, and so are temporary
+/// (privacy), deprecated and tentative IPv6 addresses where the kernel reports those flags.
+fn read_interface_ip(interface: &str, record_type: RRType) -> Result<IpAddr> {
+    let ipv6_flags = match fs::read_to_string("/proc/net/if_inet6") {
+        Ok(contents) => parse_if_inet6(&contents, interface),
+        Err(_) => Vec::new(),
+    };
+
+    select_interface_ip(interface_addresses(interface)?, &ipv6_flags, record_type).ok_or_else(
+        || {
+            NsddnsError::IpLookup(format!(
+                "interface {} has no public address for {} records",
+                interface, record_type
+            ))
+        },
+    )
+}
+
+/// First publishable address of a record type's family among an interface's addresses
+fn select_interface_ip(
+    addresses: Vec<IpAddr>,
+    ipv6_flags: &[(Ipv6Addr, u32)],
+    record_type: RRType,
+) -> Option<IpAddr> {
+    addresses
+        .into_iter()
+        .filter(|ip| match ip {
+            IpAddr::V4(_) => true,
+            IpAddr::V6(ip) => !ipv6_flags
+                .iter()
+                .any(|(addr, flags)| addr == ip && flags & IFA_F_UNPUBLISHABLE != 0),
+        })
+        .find_map(|ip| validate_ip(&ip.to_string(), record_type).ok())
+}
+
+/// Ask a single source for the public address
+fn query_ip_source(client: &HttpClient, source: &IpSource) -> Result<IpAddr> {
+    match &source.kind {
+        IpSourceKind::Http { url, format } => {
+            let request = client.get(url).build()?;
+            let body = client
+                .execute(request, RetryPolicy::Idempotent)?
+                .error_for_status()?
+                .text()?;
+
+            let value = parse_ip_response(&body, format)?;
+            validate_ip(&value, source.record_type)
+        }
+        IpSourceKind::Interface(interface) => read_interface_ip(interface, source.record_type),
+    }
+}
+
+/// Tally of the addresses reported by IP discovery sources
+#[derive(Debug, Default)]
+struct IpVotes {
+    votes: Vec<(IpAddr, usize)>,
+}
+
+impl IpVotes {
+    /// Count a vote for an address, returning the number of votes it now has
+    fn vote(&mut self, ip: IpAddr) -> usize {
+        match self.votes.iter_mut().find(|(voted, _)| *voted == ip) {
+            Some((_, count)) => {
+                *count += 1;
+                *count
+            }
+            None => {
+                self.votes.push((ip, 1));
+                1
+            }
+        }
+    }
+}
+
+/// Get the public IP of the executing machine for a record type
+///
+/// Sources for the record type are asked in order until `quorum` of them report the same valid
+/// address. With a quorum of 1, later sources only act as fallbacks for failing ones.
+pub fn get_current_ip(
+    client: &HttpClient,
+    sources: &[IpSource],
+    record_type: RRType,
+    quorum: usize,
+) -> Result<String> {
+    let sources: Vec<&IpSource> = sources
+        .iter()
+        .filter(|s| s.record_type == record_type)
+        .collect();
+    if sources.len() < quorum.max(1) {
+        return Err(NsddnsError::Config(format!(
+            "{} IP sources configured for {} records, but a quorum of {} is required",
+            sources.len(),
+            record_type,
+            quorum
+        )));
+    }
+
+    let mut votes = IpVotes::default();
+    let mut failures = Vec::new();
+    for source in sources {
+        match query_ip_source(client, source) {
+            Ok(ip) => {
+                crate::debug!("{} answered {}", source, ip);
+                if votes.vote(ip) >= quorum {
+                    return Ok(ip.to_string());
+                }
+            }
+            Err(e) => {
+                crate::debug!("{} failed: {}", source, e);
+                failures.push(format!("{}: {}", source, e));
+            }
+        }
+    }
+
+    let answers: Vec<String> = votes
+        .votes
+        .iter()
+        .map(|(ip, count)| format!("{} ({} votes)", ip, count))
+        .collect();
+    Err(NsddnsError::IpLookup(format!(
+        "no {} address reached a quorum of {} (answers: [{}], failures: [{}])",
+        record_type,
+        quorum,
+        answers.join(", "),
+        failures.join("; ")
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ip_response() -> Result<()> {
+        assert_eq!(parse_ip_response("1.2.3.4\n", &IpFormat::Text)?, "1.2.3.4");
+        assert_eq!(
+            parse_ip_response(r#"{"ip": "1.2.3.4"}"#, &IpFormat::Json(String::from("ip")))?,
+            "1.2.3.4"
+        );
+        assert_eq!(
+            parse_ip_response(
+                "fl=1f1\nh=www.cloudflare.com\nip=2606:4700::1\nts=1",
+                &IpFormat::KeyValue(String::from("ip"))
+            )?,
+            "2606:4700::1"
+        );
+        assert!(parse_ip_response("<html></html>", &IpFormat::Json(String::from("ip"))).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_ip() {
+        assert!(validate_ip("8.8.8.8", RRType::A).is_ok());
+        assert!(validate_ip("2606:4700::1", RRType::AAAA).is_ok());
+        assert!(validate_ip("<html>502 Bad Gateway</html>", RRType::A).is_err());
+        assert!(validate_ip("2606:4700::1", RRType::A).is_err());
+        assert!(validate_ip("8.8.8.8", RRType::AAAA).is_err());
+        assert!(validate_ip("192.168.1.1", RRType::A).is_err());
+        assert!(validate_ip("127.0.0.1", RRType::A).is_err());
+        assert!(validate_ip("100.64.0.1", RRType::A).is_err());
+        assert!(validate_ip("100.127.255.254", RRType::A).is_err());
+        assert!(validate_ip("100.128.0.1", RRType::A).is_ok());
+        assert!(validate_ip("fd00::1", RRType::AAAA).is_err());
+        assert!(validate_ip("fe80::1", RRType::AAAA).is_err());
+    }
+
+    #[test]
+    fn test_parse_if_inet6() {
+        let contents = "\
+00000000000000000000000000000001 01 80 10 80       lo
+fe8000000000000000fc00fffe000001 02 40 20 80     eth0
+20010db8000000000000000000000002 02 40 00 80     eth0
+26064700000000000000000000000003 02 40 00 01     eth0
+";
+        let addresses = parse_if_inet6(contents, "eth0");
+        assert_eq!(addresses.len(), 3);
+        assert_eq!(addresses[1], ("2001:db8::2".parse().unwrap(), 0x80));
+        assert_eq!(addresses[2].1 & IFA_F_UNPUBLISHABLE, IFA_F_TEMPORARY);
+        assert!(parse_if_inet6(contents, "eth1").is_empty());
+    }
+
+    #[test]
+    fn test_select_interface_ip() {
+        let addresses: Vec<IpAddr> = [
+            "127.0.0.1",
+            "10.0.0.2",
+            "100.64.1.2",
+            "::1",
+            "fe80::1",
+            "2606:4700::3",
+            "2606:4700::4",
+            "203.0.114.5",
+        ]
+        .iter()
+        .map(|ip| ip.parse().unwrap())
+        .collect();
+        // the first global address is a temporary one
+        let ipv6_flags = [("2606:4700::3".parse().unwrap(), IFA_F_TEMPORARY)];
+
+        assert_eq!(
+            select_interface_ip(addresses.clone(), &ipv6_flags, RRType::A),
+            Some("203.0.114.5".parse().unwrap())
+        );
+        assert_eq!(
+            select_interface_ip(addresses.clone(), &ipv6_flags, RRType::AAAA),
+            Some("2606:4700::4".parse().unwrap())
+        );
+        assert_eq!(
+            select_interface_ip(addresses[..5].to_vec(), &[], RRType::A),
+            None
+        );
+        assert!(read_interface_ip("nsddns-missing0", RRType::A).is_err());
+    }
+
+    #[test]
+    fn test_ip_votes() {
+        let mut votes = IpVotes::default();
+        assert_eq!(votes.vote("8.8.8.8".parse().unwrap()), 1);
+        assert_eq!(votes.vote("1.1.1.1".parse().unwrap()), 1);
+        assert_eq!(votes.vote("8.8.8.8".parse().unwrap()), 2);
+    }
+
+    #[test]
+    fn test_quorum_larger_than_sources() {
+        let client = HttpClient::new(&Default::default()).unwrap();
+        let sources = default_ip_sources();
+        assert!(get_current_ip(&client, &sources, RRType::A, 2).is_err());
+    }
+}
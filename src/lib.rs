@@ -1,196 +1,894 @@
-use anyhow::{anyhow, Context, Result};
-use std::{fs, path::PathBuf};
+use std::{
+    env, fmt, fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 
-/// Version of the Namesilo public API
-const NAMESILO_API_VERSION: u8 = 1;
+pub mod acme;
+pub mod daemon;
+pub mod dns;
+pub mod error;
+pub mod http;
+pub mod ip;
+pub mod log;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+pub mod namesilo;
+pub mod provider;
+mod rand;
+pub mod redact;
+pub mod serve;
+pub mod state;
+pub mod zone;
+
+pub use dns::{Verification, VerifyConfig};
+pub use error::{NsddnsError, Result};
+pub use http::{HttpClient, HttpConfig};
+pub use ip::{get_current_ip, IpFormat, IpSource, IpSourceKind};
+pub use namesilo::{ApiFormat, NamesiloProvider, NAMESILO_API_URL};
+pub use provider::{build_provider, DnsProvider};
+pub use redact::Secret;
+pub use serve::{ServeClient, ServeConfig};
+
+/// Name of the provider records use when they do not pick one
+const DEFAULT_PROVIDER: &str = "namesilo";
+
+/// Config keys which can supply an API key, only one of which may be set
+const API_KEY_KEYS: [&str; 4] = [
+    "api_key",
+    "api_key_file",
+    "api_key_env",
+    "api_key_credential",
+];
+
+/// How long a cached record state is trusted before checking with the provider again
+const DEFAULT_STATE_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+/// Type of a DNS resource record
+pub enum RRType {
+    /// IPv4 address record
+    A,
+    /// IPv6 address record
+    AAAA,
+    /// Alias of another host name
+    CNAME,
+    /// Mail exchanger, with the priority as the record's distance
+    MX,
+    /// Text record, also used for ACME challenges
+    TXT,
+    /// Service location (`weight port target`), with the priority as the record's distance
+    SRV,
+    /// Certification authority authorization (`flags tag "value"`)
+    CAA,
+}
+
+impl RRType {
+    /// Record type as Namesilo (and DNS) spell it
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RRType::A => "A",
+            RRType::AAAA => "AAAA",
+            RRType::CNAME => "CNAME",
+            RRType::MX => "MX",
+            RRType::TXT => "TXT",
+            RRType::SRV => "SRV",
+            RRType::CAA => "CAA",
+        }
+    }
+
+    /// Whether records of this type hold an IP address, and can be kept up to date by nsddns
+    pub fn is_address(&self) -> bool {
+        matches!(self, RRType::A | RRType::AAAA)
+    }
+
+    /// Whether records of this type have a priority, which Namesilo calls the distance
+    pub fn has_distance(&self) -> bool {
+        matches!(self, RRType::MX | RRType::SRV)
+    }
+}
+
+impl fmt::Display for RRType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for RRType {
+    type Err = NsddnsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_uppercase().as_str() {
+            "A" => Ok(RRType::A),
+            "AAAA" => Ok(RRType::AAAA),
+            "CNAME" => Ok(RRType::CNAME),
+            "MX" => Ok(RRType::MX),
+            "TXT" => Ok(RRType::TXT),
+            "SRV" => Ok(RRType::SRV),
+            "CAA" => Ok(RRType::CAA),
+            _ => Err(NsddnsError::Parse(format!(
+                "unsupported record type: {}",
+                s
+            ))),
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 /// Configuration information for nsddns
 pub struct NsddnsConfig {
-    /// Domain to modify A records for
+    /// DNS providers which records can be managed through
+    pub providers: Vec<ProviderConfig>,
+    /// Resource records which should be kept up to date
+    pub records: Vec<RecordTarget>,
+    /// File to cache record state in between runs, if any
+    pub state_file: Option<PathBuf>,
+    /// How long a cached record state is trusted before checking with the provider again
+    pub state_max_age: Duration,
+    /// Services asked for the public IP, in order
+    pub ip_sources: Vec<IpSource>,
+    /// Number of IP sources which have to report the same address before it is used
+    pub ip_quorum: usize,
+    /// Settings of the HTTP client used for IP lookups and provider APIs
+    pub http: HttpConfig,
+    /// How the current value of a record is read before deciding to update it
+    pub check: CheckMode,
+    /// Authoritative nameservers asked by DNS checks, as host names or addresses with a port
+    pub nameservers: Vec<String>,
+    /// How updated records are checked against the authoritative nameservers, if at all
+    pub verify: Option<VerifyConfig>,
+    /// Settings of the dyndns2 update server, if configured
+    pub serve: Option<ServeConfig>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+/// Where the current value of a record is read from
+pub enum CheckMode {
+    /// List the domain's records through the provider's API
+    #[default]
+    Api,
+    /// Ask the authoritative nameservers, and only call the API if the value differs
+    Dns,
+}
+
+impl FromStr for CheckMode {
+    type Err = NsddnsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "api" => Ok(CheckMode::Api),
+            "dns" => Ok(CheckMode::Dns),
+            _ => Err(NsddnsError::Parse(format!("unsupported check mode: {}", s))),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Backend which hosts the DNS records of a domain
+pub enum ProviderKind {
+    /// Namesilo public API
+    Namesilo,
+}
+
+impl FromStr for ProviderKind {
+    type Err = NsddnsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "namesilo" => Ok(ProviderKind::Namesilo),
+            _ => Err(NsddnsError::Parse(format!(
+                "unsupported provider kind: {}",
+                s
+            ))),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+/// Configuration for a single named DNS provider
+pub struct ProviderConfig {
+    /// Name records use to refer to this provider
+    pub name: String,
+    /// Backend the provider talks to
+    pub kind: ProviderKind,
+    /// API key for reading/mutating records
+    pub api_key: Secret,
+    /// Format the Namesilo API is asked to reply in
+    pub api_format: ApiFormat,
+    /// Base URL of the provider's API
+    pub api_url: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// A single resource record which nsddns keeps pointed at the current IP
+pub struct RecordTarget {
+    /// Domain the record belongs to
     pub domain: String,
     /// Subdomain (or blank if mutating the apex)
     pub subdomain: String,
-    /// Namesilo API key for reading/mutating records
-    pub api_key: String,
+    /// Type of the record, which decides whether it holds the IPv4 or IPv6 address
+    pub record_type: RRType,
+    /// Name of the provider which hosts the record
+    pub provider: String,
+    /// Create the record with the current IP if the provider does not have it yet
+    pub create_missing: bool,
+    /// TTL to give the record, instead of keeping the one it has
+    pub ttl: Option<u32>,
 }
 
-#[derive(Clone, Debug)]
+impl RecordTarget {
+    /// Fully qualified host name of the record, as Namesilo reports it
+    pub fn host(&self) -> String {
+        // an empty subdomain means that we should just use the apex domain
+        if self.subdomain.is_empty() {
+            self.domain.to_owned()
+        } else {
+            format!("{}.{}", self.subdomain, self.domain)
+        }
+    }
+}
+
+impl NsddnsConfig {
+    /// Unique record types referenced by the configured records, in config order
+    pub fn record_types(&self) -> Vec<RRType> {
+        let mut record_types = Vec::new();
+        for record in &self.records {
+            if !record_types.contains(&record.record_type) {
+                record_types.push(record.record_type);
+            }
+        }
+        record_types
+    }
+
+    /// Unique (provider, domain) pairs referenced by the configured records, in config order
+    pub fn zones(&self) -> Vec<(&str, &str)> {
+        let mut zones: Vec<(&str, &str)> = Vec::new();
+        for record in &self.records {
+            let zone = (record.provider.as_str(), record.domain.as_str());
+            if !zones.contains(&zone) {
+                zones.push(zone);
+            }
+        }
+        zones
+    }
+
+    /// Look up a provider config by name
+    pub fn provider(&self, name: &str) -> Option<&ProviderConfig> {
+        self.providers.iter().find(|p| p.name == name)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 /// DNS resource record representation
 pub struct NsResourceRecord {
     /// Host for the resource record (domain)
     pub record_host: String,
+    /// Type of the resource record
+    pub record_type: RRType,
     /// Value of the resource record
     pub record_value: String,
-    /// Namesilo's ID for the resource record
+    /// Provider's ID for the resource record
     pub record_id: String,
+    /// Time to live in seconds, if the provider reported it
+    pub ttl: Option<u32>,
+    /// Priority of MX and SRV records, if the provider reported it
+    pub distance: Option<u32>,
+}
+
+impl NsResourceRecord {
+    /// A record which does not exist at the provider yet, to be passed to `create_record`
+    pub fn new(record_host: &str, record_type: RRType, record_value: &str) -> Self {
+        NsResourceRecord {
+            record_host: record_host.to_owned(),
+            record_type,
+            record_value: record_value.to_owned(),
+            record_id: String::new(),
+            ttl: None,
+            distance: None,
+        }
+    }
+
+    /// Host of the record relative to its domain (blank for the apex)
+    pub fn subdomain(&self, domain: &str) -> &str {
+        if self.record_host == domain {
+            return "";
+        }
+        self.record_host
+            .strip_suffix(domain)
+            .and_then(|host| host.strip_suffix('.'))
+            .unwrap_or(&self.record_host)
+    }
 }
 
 /// Parse the configuration JSON and return a NsddnsConfig struct
 pub fn parse_config(cfg: PathBuf) -> Result<NsddnsConfig> {
     let path = cfg.as_path();
     let config_data = fs::read_to_string(path)
-        .with_context(|| format!("Failed to read {}", cfg.to_string_lossy()))?;
+        .map_err(|e| NsddnsError::io(format!("Failed to read {}", cfg.to_string_lossy()), e))?;
 
-    let config_json = json::parse(&config_data)
-        .with_context(|| format!("Failed to parse {} as valid JSON", cfg.to_string_lossy()))?;
+    parse_config_json(&config_data).map_err(|e| match e {
+        NsddnsError::Config(msg) => {
+            NsddnsError::Config(format!("{}: {}", cfg.to_string_lossy(), msg))
+        }
+        e => e,
+    })
+}
 
-    let domain = match config_json["domain"].as_str() {
+/// Parse a single record target from a JSON object
+fn parse_record_target(
+    record_json: &json::JsonValue,
+    prefix: &str,
+    create_missing_default: bool,
+    ttl_default: Option<u32>,
+) -> Result<RecordTarget> {
+    let domain = match record_json["domain"].as_str() {
         Some(domain) => domain.to_owned(),
-        None => anyhow::bail!("config missing key: domain"),
+        None => return Err(missing_key(prefix, "domain")),
     };
-    let subdomain = match config_json["subdomain"].as_str() {
+    let subdomain = match record_json["subdomain"].as_str() {
         Some(subdomain) => subdomain.to_owned(),
-        None => anyhow::bail!("config missing key: subdomain"),
+        None => return Err(missing_key(prefix, "subdomain")),
     };
-    let api_key = match config_json["api_key"].as_str() {
-        Some(api_key) => api_key.to_owned(),
-        None => anyhow::bail!("config missing key: api_key"),
+    let record_type = match record_json["type"].as_str() {
+        Some(record_type) => record_type.parse().map_err(|e| {
+            NsddnsError::Config(format!("invalid config key {}type: {}", prefix, e))
+        })?,
+        None => RRType::A,
     };
+    if !record_type.is_address() {
+        return Err(NsddnsError::Config(format!(
+            "config key {}type must be A or AAAA",
+            prefix
+        )));
+    }
+    let provider = match record_json["provider"].as_str() {
+        Some(provider) => provider.to_owned(),
+        None => DEFAULT_PROVIDER.to_owned(),
+    };
+    let create_missing =
+        parse_bool(record_json, "create_missing", prefix)?.unwrap_or(create_missing_default);
+    let ttl = parse_ttl(record_json, prefix)?.or(ttl_default);
 
-    Ok(NsddnsConfig {
+    Ok(RecordTarget {
         domain,
         subdomain,
-        api_key,
+        record_type,
+        provider,
+        create_missing,
+        ttl,
     })
 }
 
-/// Parse the XML data into a vec of resource records for a namesilo listDns response
-fn parse_namesilo_a_records_xml(xml_data: String) -> Result<Vec<NsResourceRecord>> {
-    let api_response = roxmltree::Document::parse(&xml_data)?;
-    let rrs = api_response
-        .descendants()
-        .filter(|n| n.has_tag_name("resource_record"));
-
-    let mut resource_records = Vec::new();
-    for rr in rrs {
-        if !rr
-            .descendants()
-            .any(|n| n.has_tag_name("type") && n.text() == Some("A"))
-        {
-            continue;
-        }
-
-        let record_host = rr
-            .descendants()
-            .find(|n| n.has_tag_name("host"))
-            .unwrap()
-            .text()
-            .unwrap()
-            .to_owned();
-        let record_value = rr
-            .descendants()
-            .find(|n| n.has_tag_name("value"))
-            .unwrap()
-            .text()
-            .unwrap()
-            .to_owned();
-        let record_id = rr
-            .descendants()
-            .find(|n| n.has_tag_name("record_id"))
-            .unwrap()
-            .text()
-            .unwrap()
-            .to_owned();
-
-        resource_records.push(NsResourceRecord {
-            record_host,
-            record_value,
-            record_id,
-        });
+/// Parse an optional record TTL
+fn parse_ttl(config_json: &json::JsonValue, prefix: &str) -> Result<Option<u32>> {
+    match parse_u64(config_json, "ttl", prefix)? {
+        Some(ttl) if ttl == 0 || ttl > u32::MAX as u64 => Err(NsddnsError::Config(format!(
+            "config key {}ttl must be between 1 and {}",
+            prefix,
+            u32::MAX
+        ))),
+        ttl => Ok(ttl.map(|ttl| ttl as u32)),
     }
+}
 
-    Ok(resource_records)
+/// Error for a required config key which is missing
+fn missing_key(prefix: &str, key: &str) -> NsddnsError {
+    NsddnsError::Config(format!("config missing key: {}{}", prefix, key))
 }
 
-/// Get the resource record for a domain based on the NsddnsConfig
-pub fn get_namesilo_a_record(config: &NsddnsConfig) -> Result<NsResourceRecord> {
-    let client = reqwest::blocking::Client::new();
-    let response = client
-        .get("https://www.namesilo.com/api/dnsListRecords")
-        .query(&[("version", NAMESILO_API_VERSION)])
-        .query(&[
-            ("type", "xml"),
-            ("key", config.api_key.as_str()),
-            ("domain", config.domain.as_str()),
-        ])
-        .send()?
-        .text()?;
+/// Parse an optional boolean key from a JSON object
+fn parse_bool(json_value: &json::JsonValue, key: &str, prefix: &str) -> Result<Option<bool>> {
+    if json_value[key].is_null() {
+        return Ok(None);
+    }
+    match json_value[key].as_bool() {
+        Some(value) => Ok(Some(value)),
+        None => Err(NsddnsError::Config(format!(
+            "config key {}{} must be true or false",
+            prefix, key
+        ))),
+    }
+}
 
-    let resource_records = parse_namesilo_a_records_xml(response)?;
+/// Parse an optional non-negative integer key from a JSON object
+fn parse_u64(json_value: &json::JsonValue, key: &str, prefix: &str) -> Result<Option<u64>> {
+    if json_value[key].is_null() {
+        return Ok(None);
+    }
+    match json_value[key].as_u64() {
+        Some(value) => Ok(Some(value)),
+        None => Err(NsddnsError::Config(format!(
+            "config key {}{} must be a non-negative integer",
+            prefix, key
+        ))),
+    }
+}
 
-    // an empty subdomain means that we should just use the apex domain
-    let host = if config.subdomain.is_empty() {
-        config.domain.to_owned()
-    } else {
-        format!("{}.{}", config.subdomain, config.domain)
+/// Parse a single IP discovery source from a JSON object
+fn parse_ip_source(source_json: &json::JsonValue, prefix: &str) -> Result<IpSource> {
+    let record_type = match source_json["type"].as_str() {
+        Some(record_type) => record_type.parse().map_err(|e| {
+            NsddnsError::Config(format!("invalid config key {}type: {}", prefix, e))
+        })?,
+        None => RRType::A,
     };
+    if let Some(interface) = source_json["interface"].as_str() {
+        return Ok(IpSource::interface(interface, record_type));
+    }
 
-    let ns_record = match resource_records
-        .into_iter()
-        .find(|rr| rr.record_host == host)
-    {
-        Some(rr) => rr,
+    let url = match source_json["url"].as_str() {
+        Some(url) => url.to_owned(),
         None => {
-            anyhow::bail!(
-                "No matching host record for '{}' in apex domain '{}'",
-                host,
-                config.domain
-            )
+            return Err(NsddnsError::Config(format!(
+                "config missing key: {}url or {}interface",
+                prefix, prefix
+            )))
         }
     };
+    let key = source_json["key"].as_str().unwrap_or("ip").to_owned();
+    let format = match source_json["parse"].as_str().unwrap_or("text") {
+        "text" => IpFormat::Text,
+        "json" => IpFormat::Json(key),
+        "kv" => IpFormat::KeyValue(key),
+        other => {
+            return Err(NsddnsError::Config(format!(
+                "invalid config key: {}parse must be text, json or kv, not {}",
+                prefix, other
+            )))
+        }
+    };
+
+    Ok(IpSource {
+        record_type,
+        kind: IpSourceKind::Http { url, format },
+    })
+}
+
+/// Parse a single named provider from a JSON object
+fn parse_provider_config(name: &str, provider_json: &json::JsonValue) -> Result<ProviderConfig> {
+    let kind = match provider_json["kind"].as_str() {
+        Some(kind) => kind.parse().map_err(|e| {
+            NsddnsError::Config(format!("invalid config key providers.{}.kind: {}", name, e))
+        })?,
+        None => ProviderKind::Namesilo,
+    };
+    let prefix = format!("providers.{}.", name);
+    let api_key = match parse_api_key(provider_json, &prefix)? {
+        Some(api_key) => api_key,
+        None => return Err(missing_key(&prefix, "api_key")),
+    };
+
+    Ok(ProviderConfig {
+        name: name.to_owned(),
+        kind,
+        api_key,
+        api_format: parse_api_format(provider_json, &prefix)?,
+        api_url: parse_api_url(provider_json, &prefix)?,
+    })
+}
+
+/// Parse the optional API base URL of a provider
+fn parse_api_url(config_json: &json::JsonValue, prefix: &str) -> Result<String> {
+    match config_json["api_url"].as_str() {
+        Some(api_url) if api_url.starts_with("http://") || api_url.starts_with("https://") => {
+            Ok(api_url.trim_end_matches('/').to_owned())
+        }
+        Some(_) => Err(NsddnsError::Config(format!(
+            "config key {}api_url must be an http:// or https:// URL",
+            prefix
+        ))),
+        None => Ok(NAMESILO_API_URL.to_owned()),
+    }
+}
+
+/// Parse the optional HTTP client settings
+fn parse_http_config(config_json: &json::JsonValue) -> Result<HttpConfig> {
+    let http_json = &config_json["http"];
+    if http_json.is_null() {
+        return Ok(HttpConfig::default());
+    }
+    if !http_json.is_object() {
+        return Err(NsddnsError::Config(String::from(
+            "config key http must be an object",
+        )));
+    }
+
+    let mut http = HttpConfig::default();
+    for (key, setting) in [
+        ("timeout", &mut http.timeout),
+        ("connect_timeout", &mut http.connect_timeout),
+    ] {
+        if let Some(timeout) = parse_u64(http_json, key, "http.")? {
+            if timeout == 0 {
+                return Err(NsddnsError::Config(format!(
+                    "config key http.{} must be at least 1",
+                    key
+                )));
+            }
+            *setting = Duration::from_secs(timeout);
+        }
+    }
+    if let Some(retries) = parse_u64(http_json, "retries", "http.")? {
+        http.retries = retries.min(u32::MAX as u64) as u32;
+    }
+    if let Some(retry_backoff) = parse_u64(http_json, "retry_backoff", "http.")? {
+        http.retry_backoff = Duration::from_secs(retry_backoff);
+    }
+    if let Some(user_agent) = http_json["user_agent"].as_str() {
+        http.user_agent = user_agent.to_owned();
+    }
+    http.proxy = http_json["proxy"].as_str().map(|proxy| proxy.to_owned());
+    Ok(http)
+}
+
+/// Parse the optional `serve` object of the dyndns2 update server
+fn parse_serve_config(
+    config_json: &json::JsonValue,
+    records: &[RecordTarget],
+) -> Result<Option<ServeConfig>> {
+    let serve_json = &config_json["serve"];
+    if serve_json.is_null() {
+        return Ok(None);
+    }
+    if !serve_json.is_object() {
+        return Err(NsddnsError::Config(String::from(
+            "config key serve must be an object",
+        )));
+    }
+
+    let listen = serve_json["listen"]
+        .as_str()
+        .unwrap_or(serve::DEFAULT_LISTEN)
+        .to_owned();
+    if !serve_json["clients"].is_array() || serve_json["clients"].is_empty() {
+        return Err(NsddnsError::Config(String::from(
+            "config key serve.clients must be a non-empty list",
+        )));
+    }
+
+    let mut clients = Vec::new();
+    for (i, client_json) in serve_json["clients"].members().enumerate() {
+        let prefix = format!("serve.clients[{}].", i);
+        let username = client_json["username"]
+            .as_str()
+            .ok_or_else(|| missing_key(&prefix, "username"))?;
+        let password = client_json["password"]
+            .as_str()
+            .ok_or_else(|| missing_key(&prefix, "password"))?;
+        if !client_json["hosts"].is_array() || client_json["hosts"].is_empty() {
+            return Err(NsddnsError::Config(format!(
+                "config key {}hosts must be a non-empty list",
+                prefix
+            )));
+        }
+
+        let mut hosts = Vec::new();
+        for host in client_json["hosts"].members() {
+            let host = host
+                .as_str()
+                .map(|host| host.trim_end_matches('.').to_ascii_lowercase())
+                .ok_or_else(|| {
+                    NsddnsError::Config(format!(
+                        "config key {}hosts must only contain strings",
+                        prefix
+                    ))
+                })?;
+            if !records.iter().any(|r| r.host().eq_ignore_ascii_case(&host)) {
+                return Err(NsddnsError::Config(format!(
+                    "config key {}hosts lists '{}', which is not a configured record",
+                    prefix, host
+                )));
+            }
+            hosts.push(host);
+        }
+
+        clients.push(ServeClient {
+            username: username.to_owned(),
+            password: Secret::unlisted(password),
+            hosts,
+        });
+    }
 
-    Ok(ns_record)
+    Ok(Some(ServeConfig { listen, clients }))
 }
 
-/// Validate that the namesilo response has a code of 300 (success)
-fn validate_reply_code(response_xml: &str) -> Result<()> {
-    let api_response = roxmltree::Document::parse(response_xml)?;
-    if api_response
-        .descendants()
-        .find(|n| n.has_tag_name("reply"))
-        .is_some_and(|r| {
-            r.descendants()
-                .any(|c| c.has_tag_name("code") && c.text().unwrap_or_default() == "300")
+/// Parse an optional list of nameservers
+fn parse_nameservers(config_json: &json::JsonValue, prefix: &str) -> Result<Option<Vec<String>>> {
+    let nameservers = &config_json["nameservers"];
+    if nameservers.is_null() {
+        return Ok(None);
+    }
+    if !nameservers.is_array() || nameservers.is_empty() {
+        return Err(NsddnsError::Config(format!(
+            "config key {}nameservers must be a non-empty list",
+            prefix
+        )));
+    }
+    nameservers
+        .members()
+        .map(|ns| {
+            ns.as_str().map(|ns| ns.to_owned()).ok_or_else(|| {
+                NsddnsError::Config(format!(
+                    "config key {}nameservers must only contain strings",
+                    prefix
+                ))
+            })
         })
-    {
-        return Ok(());
+        .collect::<Result<Vec<_>>>()
+        .map(Some)
+}
+
+/// Parse the optional `verify` setting, either `true` or an object of settings
+///
+/// Verification asks the top-level `nameservers` unless it lists its own.
+fn parse_verify_config(
+    config_json: &json::JsonValue,
+    nameservers: &[String],
+) -> Result<Option<VerifyConfig>> {
+    let mut verify = VerifyConfig {
+        nameservers: nameservers.to_vec(),
+        ..Default::default()
+    };
+    let verify_json = &config_json["verify"];
+    if verify_json.is_null() || verify_json.as_bool() == Some(false) {
+        return Ok(None);
+    }
+    if verify_json.as_bool() == Some(true) {
+        return Ok(Some(verify));
+    }
+    if !verify_json.is_object() {
+        return Err(NsddnsError::Config(String::from(
+            "config key verify must be a boolean or an object",
+        )));
+    }
+
+    if let Some(nameservers) = parse_nameservers(verify_json, "verify.")? {
+        verify.nameservers = nameservers;
+    }
+    if let Some(timeout) = parse_u64(verify_json, "timeout", "verify.")? {
+        verify.timeout = Duration::from_secs(timeout);
+    }
+    if let Some(interval) = parse_u64(verify_json, "interval", "verify.")? {
+        verify.interval = Duration::from_secs(interval);
+    }
+    Ok(Some(verify))
+}
+
+/// Parse the optional Namesilo API format of a provider
+fn parse_api_format(config_json: &json::JsonValue, prefix: &str) -> Result<ApiFormat> {
+    match config_json["api_format"].as_str() {
+        Some(api_format) => api_format.parse().map_err(|e| {
+            NsddnsError::Config(format!("invalid config key {}api_format: {}", prefix, e))
+        }),
+        None => Ok(ApiFormat::default()),
+    }
+}
+
+/// Load an API key from whichever of the `API_KEY_KEYS` is set, if any
+fn parse_api_key(config_json: &json::JsonValue, prefix: &str) -> Result<Option<Secret>> {
+    let keys: Vec<&str> = API_KEY_KEYS
+        .into_iter()
+        .filter(|key| config_json.has_key(key))
+        .collect();
+    let key = match keys.as_slice() {
+        [] => return Ok(None),
+        [key] => *key,
+        _ => {
+            return Err(NsddnsError::Config(format!(
+                "config sets more than one of {}{}",
+                prefix,
+                keys.join(", ")
+            )))
+        }
+    };
+    let Some(value) = config_json[key].as_str() else {
+        return Err(NsddnsError::Config(format!(
+            "invalid config key {}{}: must be a string",
+            prefix, key
+        )));
+    };
+
+    let api_key = match key {
+        "api_key_file" => read_key_file(Path::new(value))?,
+        "api_key_env" => match env::var(value) {
+            Ok(api_key) if !api_key.is_empty() => Secret::new(api_key),
+            _ => {
+                return Err(NsddnsError::Config(format!(
+                    "environment variable {} named by config key {}api_key_env is not set",
+                    value, prefix
+                )))
+            }
+        },
+        "api_key_credential" => {
+            // systemd's LoadCredential= puts credentials into this directory
+            let Some(credentials_dir) = env::var_os("CREDENTIALS_DIRECTORY") else {
+                return Err(NsddnsError::Config(format!(
+                    "config key {}api_key_credential is set, but $CREDENTIALS_DIRECTORY is not \
+                     (is LoadCredential= missing from the unit?)",
+                    prefix
+                )));
+            };
+            read_key_file(&Path::new(&credentials_dir).join(value))?
+        }
+        _ => Secret::new(value),
+    };
+    Ok(Some(api_key))
+}
+
+/// Read an API key from a file, which must not be readable by everyone
+fn read_key_file(path: &Path) -> Result<Secret> {
+    let metadata = fs::metadata(path)
+        .map_err(|e| NsddnsError::io(format!("Failed to read {}", path.display()), e))?;
+    if metadata.permissions().mode() & 0o004 != 0 {
+        return Err(NsddnsError::Config(format!(
+            "refusing to read API key from {}, which is world-readable (chmod o-r it)",
+            path.display()
+        )));
     }
 
-    Err(anyhow!("Namesilo API did not return success (code 300)"))
+    let api_key = fs::read_to_string(path)
+        .map_err(|e| NsddnsError::io(format!("Failed to read {}", path.display()), e))?;
+    let api_key = api_key.trim();
+    if api_key.is_empty() {
+        return Err(NsddnsError::Config(format!(
+            "API key file {} is empty",
+            path.display()
+        )));
+    }
+    Ok(Secret::new(api_key))
 }
 
-/// Update a namesilo resource record to a new value
-pub fn update_namesilo_a_record(
-    config: &NsddnsConfig,
-    resource_record: &NsResourceRecord,
-    new_value: &str,
-) -> Result<()> {
-    let client = reqwest::blocking::Client::new();
-    let response_xml = client
-        .get("https://www.namesilo.com/api/dnsUpdateRecord")
-        .query(&[("version", NAMESILO_API_VERSION)])
-        .query(&[
-            ("type", "xml"),
-            ("key", config.api_key.as_str()),
-            ("domain", config.domain.as_str()),
-        ])
-        .query(&[
-            ("rrhost", config.subdomain.as_str()),
-            ("rrvalue", new_value),
-            ("rrid", resource_record.record_id.as_str()),
-        ])
-        .send()?
-        .text()?;
+/// Parse the contents of a configuration file
+fn parse_config_json(config_data: &str) -> Result<NsddnsConfig> {
+    let config_json = json::parse(config_data)
+        .map_err(|e| NsddnsError::Config(format!("config is not valid JSON: {}", e)))?;
+
+    let mut providers = Vec::new();
+    if config_json.has_key("providers") {
+        if !config_json["providers"].is_object() {
+            return Err(NsddnsError::Config(String::from(
+                "config key providers must be an object",
+            )));
+        }
+        for (name, provider_json) in config_json["providers"].entries() {
+            providers.push(parse_provider_config(name, provider_json)?);
+        }
+    }
+    // a top-level api_key is shorthand for the default Namesilo provider
+    if let Some(api_key) = parse_api_key(&config_json, "")? {
+        if providers.iter().any(|p| p.name == DEFAULT_PROVIDER) {
+            return Err(NsddnsError::Config(format!(
+                "config sets both api_key and providers.{}",
+                DEFAULT_PROVIDER
+            )));
+        }
+        providers.push(ProviderConfig {
+            name: DEFAULT_PROVIDER.to_owned(),
+            kind: ProviderKind::Namesilo,
+            api_key,
+            api_format: parse_api_format(&config_json, "")?,
+            api_url: parse_api_url(&config_json, "")?,
+        });
+    }
+    if providers.is_empty() {
+        return Err(NsddnsError::Config(String::from(
+            "config missing key: api_key",
+        )));
+    }
+
+    let create_missing = parse_bool(&config_json, "create_missing", "")?.unwrap_or(false);
+    let ttl = parse_ttl(&config_json, "")?;
 
-    validate_reply_code(&response_xml)
+    // a top-level domain/subdomain pair is the original single record format
+    let records = if config_json.has_key("records") {
+        if !config_json["records"].is_array() {
+            return Err(NsddnsError::Config(String::from(
+                "config key records must be a list",
+            )));
+        }
+        config_json["records"]
+            .members()
+            .enumerate()
+            .map(|(i, record)| {
+                parse_record_target(record, &format!("records[{}].", i), create_missing, ttl)
+            })
+            .collect::<Result<Vec<_>>>()?
+    } else {
+        vec![parse_record_target(&config_json, "", create_missing, ttl)?]
+    };
+
+    // an empty list is fine for configs only used with the acme, plan and apply commands
+    for record in &records {
+        if !providers.iter().any(|p| p.name == record.provider) {
+            return Err(NsddnsError::Config(format!(
+                "record '{}' uses unknown provider '{}'",
+                record.host(),
+                record.provider
+            )));
+        }
+    }
+
+    let ip_sources = if config_json.has_key("ip_sources") {
+        if !config_json["ip_sources"].is_array() {
+            return Err(NsddnsError::Config(String::from(
+                "config key ip_sources must be a list",
+            )));
+        }
+        config_json["ip_sources"]
+            .members()
+            .enumerate()
+            .map(|(i, source)| parse_ip_source(source, &format!("ip_sources[{}].", i)))
+            .collect::<Result<Vec<_>>>()?
+    } else {
+        ip::default_ip_sources()
+    };
+    let ip_quorum = parse_u64(&config_json, "ip_quorum", "")?.unwrap_or(1) as usize;
+    if ip_quorum == 0 {
+        return Err(NsddnsError::Config(String::from(
+            "config key ip_quorum must be at least 1",
+        )));
+    }
+    for record in &records {
+        let sources = ip_sources
+            .iter()
+            .filter(|s| s.record_type == record.record_type)
+            .count();
+        if sources < ip_quorum {
+            return Err(NsddnsError::Config(format!(
+                "config key ip_quorum is {}, but only {} IP sources are configured for {} records",
+                ip_quorum, sources, record.record_type
+            )));
+        }
+    }
+
+    let state_file = config_json["state_file"].as_str().map(PathBuf::from);
+    let state_max_age = parse_u64(&config_json, "state_max_age", "")?
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_STATE_MAX_AGE);
+
+    let check = match config_json["check"].as_str() {
+        Some(check) => check
+            .parse()
+            .map_err(|e| NsddnsError::Config(format!("invalid config key check: {}", e)))?,
+        None => CheckMode::default(),
+    };
+    let nameservers = parse_nameservers(&config_json, "")?
+        .unwrap_or_else(|| dns::NAMESILO_NAMESERVERS.map(String::from).to_vec());
+
+    Ok(NsddnsConfig {
+        providers,
+        state_file,
+        state_max_age,
+        ip_sources,
+        ip_quorum,
+        http: parse_http_config(&config_json)?,
+        check,
+        verify: parse_verify_config(&config_json, &nameservers)?,
+        nameservers,
+        serve: parse_serve_config(&config_json, &records)?,
+        records,
+    })
 }
 
-/// Get the IP of the executing machine from api.ipify.org
-pub fn get_current_ip() -> Result<String> {
-    let client = reqwest::blocking::Client::new();
-    let response = client.get("https://api.ipify.org").send()?.text()?;
-    Ok(response)
+/// Find the resource record matching a record target in a domain's records
+pub fn find_record(
+    resource_records: &[NsResourceRecord],
+    target: &RecordTarget,
+) -> Result<NsResourceRecord> {
+    let host = target.host();
+    match resource_records
+        .iter()
+        .find(|rr| rr.record_host == host && rr.record_type == target.record_type)
+    {
+        Some(rr) => Ok(rr.clone()),
+        None => Err(NsddnsError::RecordNotFound {
+            host,
+            record_type: target.record_type,
+            domain: target.domain.to_owned(),
+        }),
+    }
 }
 
 #[cfg(test)]
@@ -198,24 +896,292 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_parse_xml_no_results() -> Result<()> {
-        let xml_data = String::from("<namesilo><reply><resource_record><record_id>a1234</record_id><type>CNAME</type><host>hooo</host><value>woooo</value></resource_record></reply></namesilo>");
-        let res = parse_namesilo_a_records_xml(xml_data)?;
-        assert!(res.is_empty());
+    fn test_find_record() -> Result<()> {
+        let rr = |host: &str, record_type, record_id: &str| NsResourceRecord {
+            record_host: host.to_owned(),
+            record_type,
+            record_value: String::from("1234"),
+            record_id: record_id.to_owned(),
+            ttl: None,
+            distance: None,
+        };
+        let res = vec![
+            rr("rob.example.com", RRType::A, "a1234"),
+            rr("rob.example.com", RRType::AAAA, "b5678"),
+        ];
+
+        let target = RecordTarget {
+            domain: String::from("example.com"),
+            subdomain: String::from("rob"),
+            record_type: RRType::AAAA,
+            provider: String::from(DEFAULT_PROVIDER),
+            create_missing: false,
+            ttl: None,
+        };
+        let found = find_record(&res, &target)?;
+        assert_eq!(found.record_id, "b5678");
+        assert_eq!(found.subdomain("example.com"), "rob");
+        assert_eq!(
+            rr("example.com", RRType::A, "c").subdomain("example.com"),
+            ""
+        );
+
+        let missing = RecordTarget {
+            subdomain: String::from("vpn"),
+            ..target
+        };
+        assert!(matches!(
+            find_record(&res, &missing),
+            Err(NsddnsError::RecordNotFound { host, .. }) if host == "vpn.example.com"
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_config_single_record() -> Result<()> {
+        let config = parse_config_json(
+            r#"{"domain": "example.com", "subdomain": "", "api_key": "1234abcd"}"#,
+        )?;
+        assert_eq!(config.providers.len(), 1);
+        assert_eq!(config.providers[0].api_key.expose(), "1234abcd");
+        assert!(!format!("{:?}", config).contains("1234abcd"));
+        assert_eq!(config.records[0].provider, DEFAULT_PROVIDER);
+        assert_eq!(config.records.len(), 1);
+        assert_eq!(config.records[0].host(), "example.com");
+        assert_eq!(config.records[0].record_type, RRType::A);
+        assert!(!config.records[0].create_missing);
+        assert!(config.state_file.is_none());
+        assert_eq!(config.state_max_age, DEFAULT_STATE_MAX_AGE);
+        assert_eq!(config.ip_sources, ip::default_ip_sources());
+        assert_eq!(config.ip_quorum, 1);
+        assert_eq!(config.check, CheckMode::Api);
+        assert!(config.verify.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_config_record_list() -> Result<()> {
+        let config = parse_config_json(
+            r#"{
+                "api_key": "1234abcd",
+                "create_missing": true,
+                "state_file": "/var/lib/nsddns/state.json",
+                "state_max_age": 3600,
+                "ttl": 7200,
+                "records": [
+                    {"domain": "example.com", "subdomain": "www", "ttl": 3600},
+                    {"domain": "example.org", "subdomain": "", "create_missing": false},
+                    {"domain": "example.com", "subdomain": "vpn", "type": "AAAA"}
+                ]
+            }"#,
+        )?;
+        assert_eq!(config.records.len(), 3);
+        assert_eq!(config.records[0].host(), "www.example.com");
+        assert_eq!(config.records[2].host(), "vpn.example.com");
+        assert_eq!(config.records[2].record_type, RRType::AAAA);
+        assert!(config.records[0].create_missing);
+        assert!(!config.records[1].create_missing);
+        assert_eq!(config.records[0].ttl, Some(3600));
+        assert_eq!(config.records[1].ttl, Some(7200));
+        assert_eq!(
+            config.state_file,
+            Some(PathBuf::from("/var/lib/nsddns/state.json"))
+        );
+        assert_eq!(config.state_max_age, Duration::from_secs(3600));
+        assert_eq!(config.record_types(), vec![RRType::A, RRType::AAAA]);
+        assert_eq!(
+            config.zones(),
+            vec![("namesilo", "example.com"), ("namesilo", "example.org")]
+        );
+
+        assert!(matches!(
+            parse_config_json(r#"{"api_key": "1234abcd", "records": [{"domain": "example.com"}]}"#),
+            Err(NsddnsError::Config(msg)) if msg == "config missing key: records[0].subdomain"
+        ));
+        assert!(parse_config_json(
+            r#"{"api_key": "1234abcd", "records": [{"domain": "example.com", "subdomain": "", "ttl": 0}]}"#
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_config_serve() -> Result<()> {
+        let config = parse_config_json(
+            r#"{
+                "api_key": "1234abcd",
+                "records": [{"domain": "example.com", "subdomain": "home"}],
+                "serve": {
+                    "listen": "0.0.0.0:8245",
+                    "clients": [{"username": "router", "password": "hunter2", "hosts": ["Home.example.com."]}]
+                }
+            }"#,
+        )?;
+        let serve = config.serve.as_ref().unwrap();
+        assert_eq!(serve.listen, "0.0.0.0:8245");
+        assert_eq!(serve.clients[0].username, "router");
+        assert_eq!(serve.clients[0].password.expose(), "hunter2");
+        assert_eq!(serve.clients[0].hosts, vec!["home.example.com"]);
+
+        assert!(matches!(
+            parse_config_json(
+                r#"{"api_key": "1234abcd", "domain": "example.com", "subdomain": "",
+                    "serve": {"clients": [{"username": "router", "password": "x", "hosts": ["www.example.com"]}]}}"#
+            ),
+            Err(NsddnsError::Config(msg)) if msg.contains("not a configured record")
+        ));
+        assert!(matches!(
+            parse_config_json(
+                r#"{"api_key": "1234abcd", "domain": "example.com", "subdomain": "",
+                    "serve": {"clients": [{"username": "router", "hosts": ["example.com"]}]}}"#
+            ),
+            Err(NsddnsError::Config(msg)) if msg == "config missing key: serve.clients[0].password"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_config_ip_sources() -> Result<()> {
+        let config = parse_config_json(
+            r#"{
+                "api_key": "1234abcd",
+                "records": [{"domain": "example.com", "subdomain": ""}],
+                "ip_sources": [
+                    {"url": "https://api.ipify.org"},
+                    {"url": "https://api.ipify.org?format=json", "parse": "json"},
+                    {"url": "https://[2606:4700:4700::1111]/cdn-cgi/trace", "type": "AAAA", "parse": "kv"},
+                    {"interface": "eth0", "type": "AAAA"}
+                ],
+                "ip_quorum": 2
+            }"#,
+        )?;
+        assert_eq!(config.ip_quorum, 2);
+        assert_eq!(config.ip_sources.len(), 4);
+        assert!(matches!(
+            &config.ip_sources[1].kind,
+            IpSourceKind::Http { format: IpFormat::Json(key), .. } if key == "ip"
+        ));
+        assert_eq!(config.ip_sources[2].record_type, RRType::AAAA);
+        assert!(matches!(
+            &config.ip_sources[2].kind,
+            IpSourceKind::Http { format: IpFormat::KeyValue(key), .. } if key == "ip"
+        ));
+        assert_eq!(
+            config.ip_sources[3],
+            IpSource::interface("eth0", RRType::AAAA)
+        );
+
+        assert!(parse_config_json(
+            r#"{"api_key": "1234abcd", "domain": "example.com", "subdomain": "", "ip_sources": [{"url": "https://x", "parse": "regex"}]}"#
+        )
+        .is_err());
+
+        // the quorum has to be reachable for every record type in use
+        assert!(matches!(
+            parse_config_json(
+                r#"{"api_key": "1234abcd", "records": [{"domain": "example.com", "subdomain": "", "type": "AAAA"}],
+                    "ip_sources": [{"url": "https://x"}, {"url": "https://y"}, {"url": "https://z", "type": "AAAA"}],
+                    "ip_quorum": 2}"#
+            ),
+            Err(NsddnsError::Config(msg)) if msg.contains("only 1 IP sources are configured for AAAA")
+        ));
+        assert!(parse_config_json(
+            r#"{"api_key": "1234abcd", "domain": "example.com", "subdomain": "",
+                "ip_sources": [{"url": "https://x"}, {"url": "https://y"}, {"url": "https://z", "type": "AAAA"}],
+                "ip_quorum": 2}"#
+        )
+        .is_ok());
         Ok(())
     }
 
     #[test]
-    fn test_parse_xml_one_record() -> Result<()> {
-        let xml_data = String::from("<namesilo><reply><resource_record><record_id>a1234</record_id><type>A</type><host>rob</host><value>1234</value></resource_record></reply></namesilo>");
-        let res = parse_namesilo_a_records_xml(xml_data)?;
-        assert!(res.len() == 1);
+    fn test_parse_config_providers() -> Result<()> {
+        let config = parse_config_json(
+            r#"{
+                "providers": {
+                    "personal": {"kind": "namesilo", "api_key": "1234abcd"},
+                    "work": {"api_key": "5678efgh", "api_url": "http://127.0.0.1:8080/api/"}
+                },
+                "http": {"timeout": 5, "proxy": "http://proxy.example.com:3128"},
+                "check": "dns",
+                "nameservers": ["127.0.0.1:5353"],
+                "verify": {"timeout": 10},
+                "records": [
+                    {"domain": "example.com", "subdomain": "www", "provider": "personal"},
+                    {"domain": "example.org", "subdomain": "", "provider": "work"}
+                ]
+            }"#,
+        )?;
+        assert_eq!(config.providers.len(), 2);
+        assert_eq!(
+            config.provider("work").unwrap().api_key.expose(),
+            "5678efgh"
+        );
+        assert_eq!(
+            config.provider("work").unwrap().api_url,
+            "http://127.0.0.1:8080/api"
+        );
+        assert_eq!(
+            config.provider("personal").unwrap().api_url,
+            NAMESILO_API_URL
+        );
+        assert_eq!(config.http.timeout, Duration::from_secs(5));
+        assert_eq!(config.http.user_agent, http::DEFAULT_USER_AGENT);
+        assert_eq!(
+            config.http.proxy.as_deref(),
+            Some("http://proxy.example.com:3128")
+        );
+        assert_eq!(config.check, CheckMode::Dns);
+        let verify = config.verify.as_ref().unwrap();
+        assert_eq!(verify.nameservers, vec!["127.0.0.1:5353"]);
+        assert_eq!(verify.timeout, Duration::from_secs(10));
+        assert_eq!(verify.interval, VerifyConfig::default().interval);
+        assert_eq!(
+            config.zones(),
+            vec![("personal", "example.com"), ("work", "example.org")]
+        );
+
+        assert!(parse_config_json(
+            r#"{"api_key": "1234abcd", "records": [{"domain": "example.com", "subdomain": "", "provider": "work"}]}"#
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_config_api_key_sources() -> Result<()> {
+        let dir = env::temp_dir().join(format!("nsddns-key-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let key_file = dir.join("api_key");
+        fs::write(&key_file, "1234abcd\n").unwrap();
+        fs::set_permissions(&key_file, fs::Permissions::from_mode(0o600)).unwrap();
+
+        let config_from_file = format!(
+            r#"{{"domain": "example.com", "subdomain": "", "api_key_file": "{}"}}"#,
+            key_file.display()
+        );
+        let config = parse_config_json(&config_from_file)?;
+        assert_eq!(config.providers[0].api_key.expose(), "1234abcd");
+
+        fs::set_permissions(&key_file, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(parse_config_json(&config_from_file).is_err());
+
+        let env_name = format!("NSDDNS_TEST_API_KEY_{}", std::process::id());
+        env::set_var(&env_name, "5678efgh");
+        let config = parse_config_json(&format!(
+            r#"{{"providers": {{"work": {{"api_key_env": "{}"}}}}, "domain": "example.com", "subdomain": "", "provider": "work"}}"#,
+            env_name
+        ))?;
+        assert_eq!(config.providers[0].api_key.expose(), "5678efgh");
 
-        let rr = res.first().unwrap();
-        assert_eq!(rr.record_id, "a1234");
-        assert_eq!(rr.record_host, "rob");
-        assert_eq!(rr.record_value, "1234");
+        assert!(parse_config_json(&format!(
+            r#"{{"api_key": "1234abcd", "api_key_env": "{}", "domain": "example.com", "subdomain": ""}}"#,
+            env_name
+        ))
+        .is_err());
 
+        fs::remove_dir_all(&dir).unwrap();
         Ok(())
     }
 }
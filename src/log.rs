@@ -0,0 +1,338 @@
+use std::{
+    env,
+    fmt::{self, Display},
+    io::{self, Write},
+    os::unix::net::UnixDatagram,
+    str::FromStr,
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::redact;
+
+/// Socket of the systemd journal's native protocol
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Identifier attached to every journal entry
+const SYSLOG_IDENTIFIER: &str = "nsddns";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+/// Severity of a log message, from most to least severe
+pub enum Level {
+    /// Something failed
+    Error,
+    /// Something looks wrong, but the run can continue
+    Warn,
+    /// A record was changed (or would have been, in a dry run)
+    Notice,
+    /// Progress of a run
+    Info,
+    /// Details useful when something does not work as expected
+    Debug,
+}
+
+impl Level {
+    /// Name of the level as printed in text and JSON output
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARNING",
+            Level::Notice => "NOTICE",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+        }
+    }
+
+    /// syslog priority of the level, as used by the journal
+    fn priority(&self) -> u8 {
+        match self {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Notice => 5,
+            Level::Info => 6,
+            Level::Debug => 7,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Where and how log messages are written
+pub enum LogFormat {
+    /// Human readable lines on stderr
+    Text,
+    /// One JSON object per line on stderr
+    Json,
+    /// Entries with structured fields in the systemd journal
+    Journald,
+}
+
+impl LogFormat {
+    /// Journald if stderr is connected to the journal, text otherwise
+    pub fn detect() -> LogFormat {
+        if stderr_is_journal() {
+            LogFormat::Journald
+        } else {
+            LogFormat::Text
+        }
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            "journald" => Ok(LogFormat::Journald),
+            _ => Err(format!(
+                "unknown log format '{}', expected text, json or journald",
+                s
+            )),
+        }
+    }
+}
+
+/// Whether systemd connected stderr to the journal (see systemd.exec(5), `$JOURNAL_STREAM`)
+fn stderr_is_journal() -> bool {
+    let Ok(journal_stream) = env::var("JOURNAL_STREAM") else {
+        return false;
+    };
+    let Some((dev, ino)) = journal_stream.split_once(':') else {
+        return false;
+    };
+    let (Ok(dev), Ok(ino)) = (dev.parse::<u64>(), ino.parse::<u64>()) else {
+        return false;
+    };
+
+    // SAFETY: fstat only writes into the zeroed stat buffer it is given
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstat(libc::STDERR_FILENO, &mut stat) } != 0 {
+        return false;
+    }
+    stat.st_dev as u64 == dev && stat.st_ino as u64 == ino
+}
+
+/// Process wide logger, set up once by `init`
+struct Logger {
+    max_level: Level,
+    format: LogFormat,
+    journal: Option<UnixDatagram>,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// Set up logging for the process
+///
+/// Messages less severe than `max_level` are dropped. Until this is called, messages up to
+/// `Level::Info` are written as text. Falls back to text if the journal cannot be reached.
+pub fn init(max_level: Level, format: LogFormat) {
+    let journal = match format {
+        LogFormat::Journald => UnixDatagram::unbound().ok(),
+        _ => None,
+    };
+    let format = match (format, &journal) {
+        (LogFormat::Journald, None) => LogFormat::Text,
+        (format, _) => format,
+    };
+
+    let _ = LOGGER.set(Logger {
+        max_level,
+        format,
+        journal,
+    });
+}
+
+/// Whether messages of a level are written at all
+pub fn enabled(level: Level) -> bool {
+    let max_level = LOGGER.get().map_or(Level::Info, |logger| logger.max_level);
+    level <= max_level
+}
+
+/// Write a log message with structured fields
+///
+/// Field names follow the journal's conventions (upper case, e.g. `DOMAIN` or `NEW_IP`); JSON
+/// output uses them in lower case. Prefer the `error!`, `warn!`, `notice!`, `info!` and `debug!`
+/// macros over calling this directly.
+pub fn log(level: Level, fields: &[(&str, &dyn Display)], message: fmt::Arguments) {
+    if !enabled(level) {
+        return;
+    }
+
+    let (format, journal) = match LOGGER.get() {
+        Some(logger) => (logger.format, logger.journal.as_ref()),
+        None => (LogFormat::Text, None),
+    };
+    // scrub credentials which slipped into the message or fields despite `Secret`
+    let message = redact::redact_text(&message.to_string()).into_owned();
+    let fields: Vec<(&str, String)> = fields
+        .iter()
+        .map(|(name, value)| (*name, redact::redact_text(&value.to_string()).into_owned()))
+        .collect();
+    let fields: Vec<(&str, &dyn Display)> = fields
+        .iter()
+        .map(|(name, value)| (*name, value as &dyn Display))
+        .collect();
+
+    if let (LogFormat::Journald, Some(journal)) = (format, journal) {
+        let entry = journal_entry(level, &fields, &message);
+        if journal.send_to(&entry, JOURNALD_SOCKET).is_ok() {
+            return;
+        }
+    }
+
+    let line = match format {
+        LogFormat::Json => json_line(level, &fields, &message),
+        _ => format!("{} {} {}", timestamp(now()), level.as_str(), message),
+    };
+    let _ = writeln!(io::stderr().lock(), "{}", line);
+}
+
+/// Serialize a message in the journal's native protocol
+fn journal_entry(level: Level, fields: &[(&str, &dyn Display)], message: &str) -> Vec<u8> {
+    let priority = level.priority();
+    let mut entry = Vec::new();
+    let builtin: [(&str, &dyn Display); 3] = [
+        ("MESSAGE", &message),
+        ("PRIORITY", &priority),
+        ("SYSLOG_IDENTIFIER", &SYSLOG_IDENTIFIER),
+    ];
+
+    for (name, value) in builtin.iter().chain(fields) {
+        let value = value.to_string();
+        entry.extend_from_slice(name.as_bytes());
+        if value.contains('\n') {
+            // values spanning lines are sent with an explicit little endian length instead
+            entry.push(b'\n');
+            entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            entry.push(b'=');
+        }
+        entry.extend_from_slice(value.as_bytes());
+        entry.push(b'\n');
+    }
+    entry
+}
+
+/// Serialize a message as a single JSON line
+fn json_line(level: Level, fields: &[(&str, &dyn Display)], message: &str) -> String {
+    let mut line = json::object! {
+        timestamp: timestamp(now()),
+        level: level.as_str().to_lowercase(),
+        message: message,
+    };
+    for (name, value) in fields {
+        line[name.to_lowercase()] = value.to_string().into();
+    }
+    line.dump()
+}
+
+/// Current unix time in seconds
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Format a unix time as an RFC 3339 UTC timestamp
+fn timestamp(unix_secs: u64) -> String {
+    let days = unix_secs / 86400;
+    let secs_of_day = unix_secs % 86400;
+
+    // civil date from days since 1970-01-01, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+/// Log a message at a level, optionally with structured fields
+///
+/// `log_event!(Level::Info, "text {}", arg)` or
+/// `log_event!(Level::Info, { "DOMAIN" => domain }, "text {}", arg)`
+#[macro_export]
+macro_rules! log_event {
+    ($level:expr, { $($name:literal => $value:expr),+ $(,)? }, $($arg:tt)+) => {
+        if $crate::log::enabled($level) {
+            $crate::log::log(
+                $level,
+                &[$(($name, &$value as &dyn ::std::fmt::Display)),+],
+                format_args!($($arg)+),
+            )
+        }
+    };
+    ($level:expr, $($arg:tt)+) => {
+        if $crate::log::enabled($level) {
+            $crate::log::log($level, &[], format_args!($($arg)+))
+        }
+    };
+}
+
+/// Log a failure, see `log_event!`
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => { $crate::log_event!($crate::log::Level::Error, $($arg)+) };
+}
+
+/// Log a warning, see `log_event!`
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => { $crate::log_event!($crate::log::Level::Warn, $($arg)+) };
+}
+
+/// Log a record change, see `log_event!`
+#[macro_export]
+macro_rules! notice {
+    ($($arg:tt)+) => { $crate::log_event!($crate::log::Level::Notice, $($arg)+) };
+}
+
+/// Log progress, see `log_event!`
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::log_event!($crate::log::Level::Info, $($arg)+) };
+}
+
+/// Log debugging details, see `log_event!`
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => { $crate::log_event!($crate::log::Level::Debug, $($arg)+) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(timestamp(951782400), "2000-02-29T00:00:00Z");
+        assert_eq!(timestamp(1700000000), "2023-11-14T22:13:20Z");
+    }
+
+    #[test]
+    fn test_journal_entry() {
+        let entry = journal_entry(Level::Notice, &[("NEW_IP", &"1.2.3.4")], "line\nbreak");
+
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&10u64.to_le_bytes());
+        expected.extend_from_slice(b"line\nbreak\n");
+        expected.extend_from_slice(b"PRIORITY=5\nSYSLOG_IDENTIFIER=nsddns\nNEW_IP=1.2.3.4\n");
+        assert_eq!(entry, expected);
+    }
+}
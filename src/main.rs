@@ -1,16 +1,52 @@
-use std::path::PathBuf;
+use std::{
+    collections::HashMap,
+    net::TcpListener,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use signal_hook::consts::{SIGINT, SIGTERM};
 
-use nsddns::{get_current_ip, get_namesilo_a_record, parse_config, update_namesilo_a_record};
+use nsddns::{
+    acme, build_provider,
+    daemon::{sleep_unless_stopped, Schedule},
+    debug,
+    dns::{serves_value, verify_record},
+    error, find_record, get_current_ip, info,
+    log::{self, Level, LogFormat},
+    notice, parse_config,
+    serve::{self, UpdateReply},
+    state::State,
+    warn,
+    zone::{self, Zone},
+    CheckMode, DnsProvider, HttpClient, NsResourceRecord, NsddnsConfig, NsddnsError, RRType,
+    RecordTarget, ServeConfig, Verification, VerifyConfig,
+};
+
+/// Time between checks whether the nameservers serve an ACME challenge
+const ACME_POLL_INTERVAL: Duration = Duration::from_secs(10);
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Enable debug logging
-    #[arg(short, long)]
+    #[arg(short, long, conflicts_with = "quiet")]
     debug: bool,
 
+    /// Only log record changes, warnings and errors
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Log output format: text, json or journald [default: journald under systemd, else text]
+    #[arg(long, value_name = "FORMAT")]
+    log_format: Option<LogFormat>,
+
     /// Configuration file to read from
     #[arg(short, long, default_value = "/etc/nsddns/conf.json")]
     config: PathBuf,
@@ -18,75 +54,1086 @@ struct Args {
     /// Do not update the resource record
     #[arg(long)]
     dry_run: bool,
+
+    /// Keep running and check the records periodically instead of exiting after one check
+    #[arg(long)]
+    daemon: bool,
+
+    /// Seconds between checks in daemon mode
+    #[arg(long, value_name = "SECS", default_value_t = 3600)]
+    interval: u64,
+
+    /// Maximum random delay in seconds added to every wait in daemon mode
+    #[arg(long, value_name = "SECS", default_value_t = 60)]
+    jitter: u64,
+
+    /// Seconds to wait after a failed check in daemon mode, doubled for every further failure
+    #[arg(long, value_name = "SECS", default_value_t = 30)]
+    backoff: u64,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Accept dyndns2 update requests (/nic/update) from routers and apply them
+    Serve {
+        /// Address and port to listen on, instead of serve.listen from the config
+        #[arg(long, value_name = "ADDR")]
+        listen: Option<String>,
+    },
+    /// Publish or remove ACME DNS-01 challenges, as a certbot manual hook or lego exec provider
+    Acme {
+        #[command(subcommand)]
+        action: AcmeAction,
+    },
+    /// Show how the live records of a domain differ from a zone file
+    Plan {
+        /// Zone file describing every record the domain should have
+        zone: PathBuf,
+    },
+    /// Create, update and delete live records until they match a zone file
+    Apply {
+        /// Zone file describing every record the domain should have
+        zone: PathBuf,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum AcmeAction {
+    /// Create the challenge TXT record and wait until the nameservers serve it
+    Present {
+        /// Domain being validated, or its full _acme-challenge name
+        #[arg(env = "CERTBOT_DOMAIN")]
+        fqdn: String,
+        /// Challenge value to publish
+        #[arg(env = "CERTBOT_VALIDATION")]
+        value: String,
+        /// Seconds to wait for the nameservers to serve the record, 0 to not wait
+        #[arg(long, value_name = "SECS", default_value_t = 900)]
+        wait: u64,
+    },
+    /// Delete the challenge TXT record (every one for the name, if no value is given)
+    Cleanup {
+        /// Domain being validated, or its full _acme-challenge name
+        #[arg(env = "CERTBOT_DOMAIN")]
+        fqdn: String,
+        /// Challenge value to remove
+        #[arg(env = "CERTBOT_VALIDATION")]
+        value: Option<String>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Result of a run, reported as the process exit code (see the README)
+enum Status {
+    /// No record needed a change
+    NoChange = 0,
+    /// The config file is missing or invalid
+    ConfigError = 3,
+    /// The public IP could not be determined
+    IpLookupFailed = 4,
+    /// Records could not be read from the provider
+    ProviderReadFailed = 5,
+    /// A record could not be updated or created, or does not exist and may not be created
+    UpdateFailed = 6,
+    /// At least one record was updated or created (or would have been, with --dry-run)
+    Updated = 10,
+}
+
+impl Status {
+    /// Whether the status reports a failure
+    fn is_failure(self) -> bool {
+        !matches!(self, Status::NoChange | Status::Updated)
+    }
+
+    /// Combine the statuses of two parts of a run
+    ///
+    /// Failures win over changes, and later stages of a run win over earlier ones.
+    fn merge(self, other: Status) -> Status {
+        match (self.is_failure(), other.is_failure()) {
+            (true, true) => {
+                if (self as u8) >= (other as u8) {
+                    self
+                } else {
+                    other
+                }
+            }
+            (true, false) => self,
+            (false, true) => other,
+            (false, false) if self == Status::Updated => self,
+            (false, false) => other,
+        }
+    }
+}
+
+impl From<Status> for ExitCode {
+    fn from(status: Status) -> Self {
+        ExitCode::from(status as u8)
+    }
+}
+
+/// Check every configured record once
+fn run_nsddns(
+    config: &NsddnsConfig,
+    client: &HttpClient,
+    state: &mut State,
+    dry_run: bool,
+) -> Status {
+    let mut status = Status::NoChange;
+
+    // each address family is only looked up once, and only if a record needs it
+    let mut current_ips: HashMap<RRType, String> = HashMap::new();
+    for record_type in config.record_types() {
+        info!("Fetching current IP address for {} records...", record_type);
+        match get_current_ip(client, &config.ip_sources, record_type, config.ip_quorum) {
+            Ok(ip) => {
+                info!("Current IP for {} records is {}.", record_type, ip);
+                current_ips.insert(record_type, ip);
+            }
+            Err(e) => {
+                error!(
+                    "failed to fetch current IP address for {} records: {}",
+                    record_type, e
+                );
+                status = status.merge(Status::IpLookupFailed);
+            }
+        }
+    }
+    if current_ips.is_empty() {
+        return status;
+    }
+
+    let providers: HashMap<&str, Box<dyn DnsProvider>> = config
+        .providers
+        .iter()
+        .map(|p| (p.name.as_str(), build_provider(p, client)))
+        .collect();
+
+    for (provider_name, domain) in config.zones() {
+        let provider = providers[provider_name].as_ref();
+
+        // only ask the provider about records whose cached state is missing, stale or outdated
+        let targets: Vec<(&RecordTarget, &String)> = config
+            .records
+            .iter()
+            .filter(|r| r.provider == provider_name && r.domain == domain)
+            .filter_map(|r| current_ips.get(&r.record_type).map(|ip| (r, ip)))
+            .filter(|(r, ip)| !state.is_current(r, ip, config.state_max_age))
+            .filter(|(r, ip)| config.check == CheckMode::Api || !dns_is_current(config, r, ip))
+            .collect();
+        if targets.is_empty() {
+            info!(
+                "Records in {} already hold the current IP, skipping.",
+                domain
+            );
+            continue;
+        }
+
+        info!(
+            "Fetching DNS information for {} from {}...",
+            domain, provider_name
+        );
+        let mut resource_records = match provider.list_records(domain) {
+            Ok(rrs) => rrs,
+            Err(e) => {
+                error!(
+                    { "DOMAIN" => domain },
+                    "Failed to fetch DNS records for {} from {}: {}",
+                    domain, provider_name, e
+                );
+                status = status.merge(Status::ProviderReadFailed);
+                continue;
+            }
+        };
+
+        for (target, current_ip) in targets {
+            status = status.merge(update_record(
+                provider,
+                target,
+                &mut resource_records,
+                current_ip,
+                state,
+                config.verify.as_ref(),
+                dry_run,
+            ));
+        }
+    }
+
+    if let Some(state_file) = &config.state_file {
+        if let Err(e) = state.save(state_file) {
+            warn!("failed to save state: {}", e);
+        }
+    }
+
+    status
 }
 
-fn run_nsddns(cfg: PathBuf, dry_run: bool) {
-    let config = parse_config(cfg).expect("config file should be valid JSON with all keys");
+/// Whether the authoritative nameservers already serve the current IP, with the configured TTL if
+/// there is one, for a record target
+fn dns_is_current(config: &NsddnsConfig, target: &RecordTarget, current_ip: &str) -> bool {
+    let host = target.host();
+    if serves_value(
+        &config.nameservers,
+        &host,
+        target.record_type,
+        current_ip,
+        target.ttl,
+    ) {
+        info!(
+            "Nameservers serve {} for {}, nothing to do.",
+            current_ip, host
+        );
+        true
+    } else {
+        info!(
+            "Nameservers do not serve {} for {}, checking with the provider.",
+            current_ip, host
+        );
+        false
+    }
+}
 
-    println!("Fetching DNS information...");
-    let resource_record = match get_namesilo_a_record(&config) {
-        Ok(dns) => dns,
+/// Load the cached record state, starting from scratch if there is none
+fn load_state(config: &NsddnsConfig) -> State {
+    let Some(state_file) = &config.state_file else {
+        return State::default();
+    };
+
+    match State::load(state_file) {
+        Ok(state) => state,
         Err(e) => {
-            println!("ERROR: Failed to fetch DNS A record from Namesilo: {:?}", e);
+            warn!("ignoring unreadable state file: {}", e);
+            State::default()
+        }
+    }
+}
+
+/// Check the records every interval until SIGTERM or SIGINT is received
+fn run_daemon(
+    config: &NsddnsConfig,
+    client: &HttpClient,
+    state: &mut State,
+    schedule: &Schedule,
+    dry_run: bool,
+) {
+    let stop = Arc::new(AtomicBool::new(false));
+    for signal in [SIGTERM, SIGINT] {
+        signal_hook::flag::register(signal, Arc::clone(&stop))
+            .expect("signal handlers should be registered");
+    }
+
+    let mut consecutive_failures = 0;
+    loop {
+        // without a state file nothing is cached, not even between the daemon's own checks
+        if config.state_file.is_none() {
+            *state = State::default();
+        }
+        if run_nsddns(config, client, state, dry_run).is_failure() {
+            consecutive_failures += 1;
+        } else {
+            consecutive_failures = 0;
+        }
+
+        let delay = schedule.next_delay(consecutive_failures);
+        info!("Next check in {} seconds.", delay.as_secs());
+        if !sleep_unless_stopped(delay, &stop) {
+            info!("Received stop signal, exiting.");
             return;
         }
+    }
+}
+
+/// Serve dyndns2 update requests until SIGTERM or SIGINT is received
+fn run_serve(
+    config: &NsddnsConfig,
+    serve_config: &ServeConfig,
+    client: &HttpClient,
+    state: &mut State,
+    dry_run: bool,
+) -> Status {
+    let listener = match TcpListener::bind(&serve_config.listen) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("failed to listen on {}: {}", serve_config.listen, e);
+            return Status::ConfigError;
+        }
     };
+    let stop = Arc::new(AtomicBool::new(false));
+    for signal in [SIGTERM, SIGINT] {
+        signal_hook::flag::register(signal, Arc::clone(&stop))
+            .expect("signal handlers should be registered");
+    }
+
+    let providers: HashMap<&str, Box<dyn DnsProvider>> = config
+        .providers
+        .iter()
+        .map(|p| (p.name.as_str(), build_provider(p, client)))
+        .collect();
+
+    info!("Listening for dyndns2 updates on {}.", serve_config.listen);
+    let result = serve::serve(&listener, serve_config, &stop, |host, record_type, ip| {
+        let Some(target) = config
+            .records
+            .iter()
+            .find(|r| r.record_type == record_type && r.host().eq_ignore_ascii_case(host))
+        else {
+            return UpdateReply::NoHost;
+        };
+        let provider = providers[target.provider.as_str()].as_ref();
+
+        let mut resource_records = match provider.list_records(&target.domain) {
+            Ok(rrs) => rrs,
+            Err(e) => {
+                error!(
+                    { "DOMAIN" => target.domain },
+                    "Failed to fetch DNS records for {} from {}: {}",
+                    target.domain, target.provider, e
+                );
+                return UpdateReply::DnsErr;
+            }
+        };
+        let status = update_record(
+            provider,
+            target,
+            &mut resource_records,
+            ip,
+            state,
+            // the client is waiting for the reply, so verification would only time it out
+            None,
+            dry_run,
+        );
+        if let Some(state_file) = &config.state_file {
+            if let Err(e) = state.save(state_file) {
+                warn!("failed to save state: {}", e);
+            }
+        }
 
-    println!("Fetching current IP address...");
-    let current_ip = match get_current_ip() {
-        Ok(ip) => ip,
+        match status {
+            Status::NoChange => UpdateReply::NoChg(ip.to_owned()),
+            Status::Updated => UpdateReply::Good(ip.to_owned()),
+            _ => UpdateReply::DnsErr,
+        }
+    });
+
+    match result {
+        Ok(()) => {
+            info!("Received stop signal, exiting.");
+            Status::NoChange
+        }
         Err(e) => {
-            println!("ERROR: failed to fetch current IP address: {:?}", e);
-            return;
+            error!("{}", e);
+            Status::ConfigError
+        }
+    }
+}
+
+/// Publish or remove an ACME challenge
+///
+/// ACME clients treat any other exit code than 0 as failure, so success is reported as NoChange.
+fn run_acme(
+    config: &NsddnsConfig,
+    client: &HttpClient,
+    action: AcmeAction,
+    dry_run: bool,
+) -> Status {
+    let fqdn = match &action {
+        AcmeAction::Present { fqdn, .. } | AcmeAction::Cleanup { fqdn, .. } => fqdn,
+    };
+    let name = acme::challenge_name(fqdn);
+
+    // the domains of configured records are the likeliest zones, and say which provider to use
+    let mut zone = None;
+    for provider_config in &config.providers {
+        let domains: Vec<&str> = config
+            .zones()
+            .into_iter()
+            .filter(|(provider, _)| *provider == provider_config.name)
+            .map(|(_, domain)| domain)
+            .collect();
+        let provider = build_provider(provider_config, client);
+        match acme::find_zone(provider.as_ref(), &domains, &name) {
+            Ok((domain, records)) => {
+                zone = Some((provider, domain, records));
+                break;
+            }
+            Err(NsddnsError::Config(_)) => continue,
+            Err(e) => {
+                error!("Failed to fetch DNS records for {}: {}", name, e);
+                return Status::ProviderReadFailed;
+            }
         }
+    }
+    let Some((provider, domain, records)) = zone else {
+        error!("No domain of any configured provider contains {}", name);
+        return Status::ConfigError;
     };
 
-    println!(
-        "DNS record value: {}.\nCurrent IP is {}.\n",
-        resource_record.record_value, current_ip,
+    if dry_run {
+        notice!(
+            { "DOMAIN" => domain, "HOST" => name },
+            "DRY RUN: would have {} the challenge TXT record for {}.",
+            match action {
+                AcmeAction::Present { .. } => "created",
+                AcmeAction::Cleanup { .. } => "deleted",
+            },
+            name
+        );
+        return Status::NoChange;
+    }
+
+    match action {
+        AcmeAction::Present { value, wait, .. } => {
+            if let Err(e) = acme::present(provider.as_ref(), &domain, &records, &name, &value) {
+                error!(
+                    { "DOMAIN" => domain, "HOST" => name },
+                    "failed to create challenge TXT record for {}: {}",
+                    name, e
+                );
+                return Status::UpdateFailed;
+            }
+            if wait == 0 {
+                return Status::NoChange;
+            }
+
+            let verify = VerifyConfig {
+                nameservers: config.nameservers.clone(),
+                timeout: Duration::from_secs(wait),
+                interval: ACME_POLL_INTERVAL,
+            };
+            info!(
+                "Waiting for the nameservers to serve the challenge for {}...",
+                name
+            );
+            match verify_record(&verify, &name, RRType::TXT, &value) {
+                Verification::Verified => {
+                    info!("Nameservers serve the challenge for {}.", name);
+                    Status::NoChange
+                }
+                Verification::Pending => {
+                    warn!(
+                        "Not all nameservers serve the challenge for {} yet, continuing anyway.",
+                        name
+                    );
+                    Status::NoChange
+                }
+                Verification::Mismatch(_) => {
+                    error!("Nameservers do not serve the challenge for {}.", name);
+                    Status::UpdateFailed
+                }
+            }
+        }
+        AcmeAction::Cleanup { value, .. } => {
+            match acme::cleanup(
+                provider.as_ref(),
+                &domain,
+                &records,
+                &name,
+                value.as_deref(),
+            ) {
+                Ok(0) => {
+                    info!("No challenge TXT record for {} to delete.", name);
+                    Status::NoChange
+                }
+                Ok(_) => Status::NoChange,
+                Err(e) => {
+                    error!(
+                        { "DOMAIN" => domain, "HOST" => name },
+                        "failed to delete challenge TXT record for {}: {}",
+                        name, e
+                    );
+                    Status::UpdateFailed
+                }
+            }
+        }
+    }
+}
+
+/// Compare the live records of a zone file's domain with the file, and apply the changes
+///
+/// With `plan_only` the changes are only printed. Either way, the status is Updated if there are
+/// any, so `nsddns plan` can be used to detect drift.
+fn run_zone(config: &NsddnsConfig, client: &HttpClient, path: &Path, plan_only: bool) -> Status {
+    let zone = match Zone::load(path) {
+        Ok(zone) => zone,
+        Err(e) => {
+            error!("{}", e);
+            return Status::ConfigError;
+        }
+    };
+    let Some(provider_config) = config.provider(&zone.provider) else {
+        error!(
+            "zone file {} uses unknown provider '{}'",
+            path.to_string_lossy(),
+            zone.provider
+        );
+        return Status::ConfigError;
+    };
+
+    let mut public_ips: HashMap<RRType, String> = HashMap::new();
+    for record_type in zone.placeholder_types() {
+        info!("Fetching current IP address for {} records...", record_type);
+        match get_current_ip(client, &config.ip_sources, record_type, config.ip_quorum) {
+            Ok(ip) => {
+                info!("Current IP for {} records is {}.", record_type, ip);
+                public_ips.insert(record_type, ip);
+            }
+            Err(e) => {
+                error!(
+                    "failed to fetch current IP address for {} records: {}",
+                    record_type, e
+                );
+                return Status::IpLookupFailed;
+            }
+        }
+    }
+    let desired = match zone.render(&public_ips) {
+        Ok(desired) => desired,
+        Err(e) => {
+            error!("{}", e);
+            return Status::ConfigError;
+        }
+    };
+
+    let provider = build_provider(provider_config, client);
+    let live = match provider.list_records(&zone.domain) {
+        Ok(live) => live,
+        Err(e) => {
+            error!(
+                { "DOMAIN" => zone.domain },
+                "Failed to fetch DNS records for {} from {}: {}",
+                zone.domain, zone.provider, e
+            );
+            return Status::ProviderReadFailed;
+        }
+    };
+
+    let changes = zone::plan(&desired, &live);
+    if changes.is_empty() {
+        info!(
+            "Records of {} match {}.",
+            zone.domain,
+            path.to_string_lossy()
+        );
+        return Status::NoChange;
+    }
+    if plan_only {
+        for change in &changes {
+            println!("{}", change);
+        }
+        return Status::Updated;
+    }
+
+    match zone::apply(provider.as_ref(), &zone.domain, &changes) {
+        Ok(()) => Status::Updated,
+        Err(e) => {
+            error!(
+                { "DOMAIN" => zone.domain },
+                "failed to apply {} to {}: {}",
+                path.to_string_lossy(),
+                zone.domain,
+                e
+            );
+            Status::UpdateFailed
+        }
+    }
+}
+
+/// Bring a single record target up to date with the current IP
+fn update_record(
+    provider: &dyn DnsProvider,
+    target: &RecordTarget,
+    resource_records: &mut Vec<NsResourceRecord>,
+    current_ip: &str,
+    state: &mut State,
+    verify: Option<&VerifyConfig>,
+    dry_run: bool,
+) -> Status {
+    let host = target.host();
+    let resource_record = match find_record(resource_records, target) {
+        Ok(rr) => rr,
+        Err(NsddnsError::RecordNotFound { .. }) if target.create_missing => {
+            return create_record(
+                provider,
+                target,
+                resource_records,
+                current_ip,
+                state,
+                verify,
+                dry_run,
+            );
+        }
+        // the record cannot be brought up to date without create_missing
+        Err(e) => {
+            error!({ "DOMAIN" => target.domain }, "{}", e);
+            return Status::UpdateFailed;
+        }
+    };
+
+    info!(
+        "DNS {} record value for {}: {}.",
+        target.record_type, host, resource_record.record_value
     );
-    if resource_record.record_value == current_ip {
-        println!("Nothing to do.");
-        return;
+    // a configured TTL replaces the record's, otherwise the TTL it has is kept
+    let new_ttl = target.ttl.or(resource_record.ttl);
+    if resource_record.record_value == current_ip && new_ttl == resource_record.ttl {
+        info!("Nothing to do for {}.", host);
+        if !dry_run {
+            state.record(target, current_ip, new_ttl);
+        }
+        return Status::NoChange;
     }
 
-    println!("Updating record....");
+    info!("Updating record for {}....", host);
     if dry_run {
-        println!(
+        notice!(
+            {
+                "DOMAIN" => target.domain,
+                "HOST" => host,
+                "RECORD_ID" => resource_record.record_id,
+                "OLD_IP" => resource_record.record_value,
+                "NEW_IP" => current_ip,
+            },
             "DRY RUN: would have updated DNS record of {:?} to {}.",
             resource_record, current_ip
         );
-        return;
+        return Status::Updated;
     }
 
-    match update_namesilo_a_record(&config, &resource_record, &current_ip) {
-        Ok(()) => println!("DNS record updated successfully"),
+    let updated_record = NsResourceRecord {
+        ttl: new_ttl,
+        ..resource_record.clone()
+    };
+    match provider.update_record(&target.domain, &updated_record, current_ip) {
+        Ok(()) => {
+            if resource_record.ttl != new_ttl {
+                info!(
+                    "TTL of {} changed from {} to {}.",
+                    host,
+                    resource_record
+                        .ttl
+                        .map_or_else(|| String::from("unknown"), |ttl| ttl.to_string()),
+                    new_ttl.unwrap_or_default()
+                );
+            }
+            notice!(
+                {
+                    "DOMAIN" => target.domain,
+                    "HOST" => host,
+                    "RECORD_ID" => resource_record.record_id,
+                    "OLD_IP" => resource_record.record_value,
+                    "NEW_IP" => current_ip,
+                },
+                "DNS {} record for {} updated from {} to {}",
+                target.record_type, host, resource_record.record_value, current_ip
+            );
+            state.record(target, current_ip, new_ttl);
+            if let Some(verify) = verify {
+                verify_update(verify, target, current_ip);
+            }
+            Status::Updated
+        }
         Err(e) => {
-            println!("ERROR: failed to update DNS record: {:?}", e);
+            error!(
+                { "DOMAIN" => target.domain, "HOST" => host, "RECORD_ID" => resource_record.record_id },
+                "failed to update DNS record for {}: {}",
+                host, e
+            );
+            Status::UpdateFailed
         }
     }
 }
 
-fn main() {
+/// Create a missing record target with the current IP
+fn create_record(
+    provider: &dyn DnsProvider,
+    target: &RecordTarget,
+    resource_records: &mut Vec<NsResourceRecord>,
+    current_ip: &str,
+    state: &mut State,
+    verify: Option<&VerifyConfig>,
+    dry_run: bool,
+) -> Status {
+    let host = target.host();
+    info!(
+        "No {} record exists for {}, creating it....",
+        target.record_type, host
+    );
+    if dry_run {
+        notice!(
+            { "DOMAIN" => target.domain, "HOST" => host, "NEW_IP" => current_ip },
+            "DRY RUN: would have created {} record for {} with value {}.",
+            target.record_type, host, current_ip
+        );
+        return Status::Updated;
+    }
+
+    let mut resource_record = NsResourceRecord {
+        ttl: target.ttl,
+        ..NsResourceRecord::new(&host, target.record_type, current_ip)
+    };
+    match provider.create_record(&target.domain, &resource_record) {
+        Ok(record_id) => {
+            notice!(
+                {
+                    "DOMAIN" => target.domain,
+                    "HOST" => host,
+                    "RECORD_ID" => record_id,
+                    "NEW_IP" => current_ip,
+                },
+                "DNS {} record for {} created with value {} and ID {}",
+                target.record_type, host, current_ip, record_id
+            );
+            state.record(target, current_ip, target.ttl);
+            resource_record.record_id = record_id;
+            resource_records.push(resource_record);
+            if let Some(verify) = verify {
+                verify_update(verify, target, current_ip);
+            }
+            Status::Updated
+        }
+        Err(e) => {
+            error!(
+                { "DOMAIN" => target.domain, "HOST" => host },
+                "failed to create DNS record for {}: {}",
+                host, e
+            );
+            Status::UpdateFailed
+        }
+    }
+}
+
+/// Check that the authoritative nameservers serve a record's new value, and report the outcome
+///
+/// The update itself went through, so the outcome is only logged and never changes the status.
+fn verify_update(verify: &VerifyConfig, target: &RecordTarget, new_ip: &str) {
+    let host = target.host();
+    info!(
+        "Waiting for the nameservers to serve {} for {}...",
+        new_ip, host
+    );
+    let verification = verify_record(verify, &host, target.record_type, new_ip);
+    match &verification {
+        Verification::Verified => info!(
+            { "DOMAIN" => target.domain, "HOST" => host, "NEW_IP" => new_ip, "VERIFICATION" => verification },
+            "Nameservers serve {} for {}.",
+            new_ip, host
+        ),
+        Verification::Pending => warn!(
+            { "DOMAIN" => target.domain, "HOST" => host, "NEW_IP" => new_ip, "VERIFICATION" => verification },
+            "Not all nameservers serve {} for {} yet, verification timed out.",
+            new_ip, host
+        ),
+        Verification::Mismatch(served) => warn!(
+            { "DOMAIN" => target.domain, "HOST" => host, "NEW_IP" => new_ip, "VERIFICATION" => verification },
+            "Nameservers still serve [{}] for {} instead of {}.",
+            served.join(", "), host, new_ip
+        ),
+    }
+}
+
+fn main() -> ExitCode {
     let args = Args::parse();
 
+    let max_level = if args.debug {
+        Level::Debug
+    } else if args.quiet {
+        Level::Notice
+    } else {
+        Level::Info
+    };
+    log::init(max_level, args.log_format.unwrap_or_else(LogFormat::detect));
+
     let cfg = args.config;
-    println!("Loading configuration from {}...", cfg.to_string_lossy());
+    info!("Loading configuration from {}...", cfg.to_string_lossy());
 
     match cfg.try_exists() {
-        Ok(true) => run_nsddns(cfg, args.dry_run),
+        Ok(true) => {}
         Ok(false) => {
-            println!(
-                "ERROR: Config file at {} does not exist",
-                cfg.to_string_lossy()
-            );
+            error!("Config file at {} does not exist", cfg.to_string_lossy());
+            return Status::ConfigError.into();
         }
         Err(e) => {
-            println!(
-                "ERROR: Failed to read config file {}: {:?}",
+            error!(
+                "Failed to read config file {}: {}",
                 cfg.to_string_lossy(),
                 e
             );
+            return Status::ConfigError.into();
         }
     }
+
+    let config = match parse_config(cfg) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{}", e);
+            return Status::ConfigError.into();
+        }
+    };
+    for target in &config.records {
+        debug!("Configured record: {:?}", target);
+    }
+    let client = match HttpClient::new(&config.http) {
+        Ok(client) => client,
+        Err(e) => {
+            error!("failed to set up the HTTP client: {}", e);
+            return Status::ConfigError.into();
+        }
+    };
+    let mut state = load_state(&config);
+
+    match args.command {
+        Some(Command::Acme { action }) => run_acme(&config, &client, action, args.dry_run).into(),
+        Some(Command::Serve { listen }) => {
+            let Some(mut serve_config) = config.serve.clone() else {
+                error!("config key serve must be set to accept dyndns2 updates");
+                return Status::ConfigError.into();
+            };
+            if let Some(listen) = listen {
+                serve_config.listen = listen;
+            }
+            run_serve(&config, &serve_config, &client, &mut state, args.dry_run).into()
+        }
+        Some(Command::Plan { zone }) => run_zone(&config, &client, &zone, true).into(),
+        Some(Command::Apply { zone }) => run_zone(&config, &client, &zone, args.dry_run).into(),
+        None if config.records.is_empty() => {
+            error!("config does not list any records");
+            Status::ConfigError.into()
+        }
+        None if args.daemon => {
+            let schedule = Schedule {
+                interval: Duration::from_secs(args.interval),
+                jitter: Duration::from_secs(args.jitter),
+                backoff: Duration::from_secs(args.backoff),
+            };
+            run_daemon(&config, &client, &mut state, &schedule, args.dry_run);
+            Status::NoChange.into()
+        }
+        None => run_nsddns(&config, &client, &mut state, args.dry_run).into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nsddns::mock::{MockNameserver, MockNamesilo};
+
+    /// Load a config written for a test, pointing at the mocks
+    fn load_config(name: &str, config_json: json::JsonValue) -> NsddnsConfig {
+        let path = std::env::temp_dir().join(format!(
+            "nsddns-main-test-{}-{}.json",
+            std::process::id(),
+            name
+        ));
+        std::fs::write(&path, config_json.dump()).unwrap();
+        let config = parse_config(path.clone()).unwrap();
+        std::fs::remove_file(path).unwrap();
+        config
+    }
+
+    fn target(subdomain: &str, create_missing: bool, ttl: Option<u32>) -> RecordTarget {
+        RecordTarget {
+            domain: String::from("example.com"),
+            subdomain: subdomain.to_owned(),
+            record_type: RRType::A,
+            provider: String::from("namesilo"),
+            create_missing,
+            ttl,
+        }
+    }
+
+    #[test]
+    fn test_status_merge() {
+        assert_eq!(Status::NoChange.merge(Status::Updated), Status::Updated);
+        assert_eq!(Status::Updated.merge(Status::NoChange), Status::Updated);
+        assert_eq!(
+            Status::Updated.merge(Status::UpdateFailed),
+            Status::UpdateFailed
+        );
+        assert_eq!(
+            Status::UpdateFailed.merge(Status::IpLookupFailed),
+            Status::UpdateFailed
+        );
+        assert_eq!(
+            Status::IpLookupFailed.merge(Status::ProviderReadFailed),
+            Status::ProviderReadFailed
+        );
+    }
+
+    #[test]
+    fn test_update_record_create_missing() -> nsddns::Result<()> {
+        let mock = MockNamesilo::start("mock-key")?;
+        mock.add_record("example.com", "www.example.com", "A", "203.0.113.1");
+        let provider = build_provider(
+            &mock.provider_config("mock-key"),
+            &HttpClient::new(&Default::default())?,
+        );
+        let mut state = State::default();
+
+        let vpn = target("vpn", true, None);
+        let mut rrs = provider.list_records("example.com")?;
+        let status = update_record(
+            provider.as_ref(),
+            &vpn,
+            &mut rrs,
+            "1.2.3.4",
+            &mut state,
+            None,
+            false,
+        );
+        assert_eq!(status, Status::Updated);
+        assert_eq!(mock.requests(), vec!["dnsListRecords", "dnsAddRecord"]);
+        let created = mock.records("example.com").pop().unwrap();
+        assert_eq!(created.record_host, "vpn.example.com");
+        assert_eq!(created.record_value, "1.2.3.4");
+        assert_eq!(state.get(&vpn).unwrap().ip, "1.2.3.4");
+        assert_eq!(rrs.len(), 2);
+
+        let missing = target("missing", false, None);
+        let status = update_record(
+            provider.as_ref(),
+            &missing,
+            &mut rrs,
+            "1.2.3.4",
+            &mut state,
+            None,
+            false,
+        );
+        assert_eq!(status, Status::UpdateFailed);
+        assert_eq!(ExitCode::from(status), ExitCode::from(6));
+        assert!(state.get(&missing).is_none());
+        assert_eq!(mock.records("example.com").len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_nsddns_dns_check() -> nsddns::Result<()> {
+        let mock = MockNamesilo::start("mock-key")?;
+        mock.add_record("example.com", "www.example.com", "A", "5.6.7.8");
+        mock.set_public_ip("5.6.7.8");
+        let nameserver = MockNameserver::start("5.6.7.8")?;
+        let config = load_config(
+            "dns-check",
+            json::object! {
+                api_key: "mock-key",
+                api_url: mock.api_url(),
+                ip_sources: [{ url: mock.ip_url() }],
+                check: "dns",
+                nameservers: [nameserver.addr()],
+                records: [{ domain: "example.com", subdomain: "www" }],
+            },
+        );
+        let client = HttpClient::new(&config.http)?;
+        let mut state = State::default();
+
+        // the nameservers already serve the current IP, so the API is not asked at all
+        assert_eq!(
+            run_nsddns(&config, &client, &mut state, false),
+            Status::NoChange
+        );
+        assert!(mock.requests().is_empty());
+
+        mock.set_public_ip("1.2.3.4");
+        assert_eq!(
+            run_nsddns(&config, &client, &mut state, false),
+            Status::Updated
+        );
+        assert_eq!(mock.requests(), vec!["dnsListRecords", "dnsUpdateRecord"]);
+        assert_eq!(mock.records("example.com")[0].record_value, "1.2.3.4");
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_record_ttl() -> nsddns::Result<()> {
+        let mock = MockNamesilo::start("mock-key")?;
+        mock.add_record("example.com", "www.example.com", "A", "1.2.3.4");
+        mock.add_record("example.com", "vpn.example.com", "A", "1.2.3.4");
+        let provider = build_provider(
+            &mock.provider_config("mock-key"),
+            &HttpClient::new(&Default::default())?,
+        );
+        let mut state = State::default();
+        let mut rrs = provider.list_records("example.com")?;
+        let mut update = |target: &RecordTarget, ip: &str, state: &mut State| {
+            update_record(provider.as_ref(), target, &mut rrs, ip, state, None, false)
+        };
+
+        // a configured TTL the record does not have is set even though the IP is current
+        let www = target("www", false, Some(600));
+        state.record(&www, "1.2.3.4", Some(3603));
+        assert!(!state.is_current(&www, "1.2.3.4", Duration::from_secs(3600)));
+        assert_eq!(update(&www, "1.2.3.4", &mut state), Status::Updated);
+        assert_eq!(mock.requests(), vec!["dnsListRecords", "dnsUpdateRecord"]);
+        let record = &mock.records("example.com")[0];
+        assert_eq!(record.record_value, "1.2.3.4");
+        assert_eq!(record.ttl, Some(600));
+        assert_eq!(state.get(&www).unwrap().ttl, Some(600));
+
+        // without a configured TTL, an update keeps the one the record has
+        let vpn = target("vpn", false, None);
+        assert_eq!(update(&vpn, "5.6.7.8", &mut state), Status::Updated);
+        let record = &mock.records("example.com")[1];
+        assert_eq!(record.record_value, "5.6.7.8");
+        assert_eq!(record.ttl, Some(3603));
+        assert_eq!(state.get(&vpn).unwrap().ttl, Some(3603));
+
+        // and a record holding both the IP and the configured TTL is left alone
+        let mut rrs = provider.list_records("example.com")?;
+        let status = update_record(
+            provider.as_ref(),
+            &www,
+            &mut rrs,
+            "1.2.3.4",
+            &mut state,
+            None,
+            false,
+        );
+        assert_eq!(status, Status::NoChange);
+        assert_eq!(
+            mock.requests(),
+            vec![
+                "dnsListRecords",
+                "dnsUpdateRecord",
+                "dnsUpdateRecord",
+                "dnsListRecords"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_nsddns_dns_check_ttl() -> nsddns::Result<()> {
+        let mock = MockNamesilo::start("mock-key")?;
+        mock.add_record("example.com", "www.example.com", "A", "5.6.7.8");
+        mock.set_public_ip("5.6.7.8");
+        let nameserver = MockNameserver::start("5.6.7.8")?;
+        let config = load_config(
+            "dns-check-ttl",
+            json::object! {
+                api_key: "mock-key",
+                api_url: mock.api_url(),
+                ip_sources: [{ url: mock.ip_url() }],
+                check: "dns",
+                nameservers: [nameserver.addr()],
+                records: [{ domain: "example.com", subdomain: "www", ttl: 600 }],
+            },
+        );
+        let client = HttpClient::new(&config.http)?;
+
+        // no state is kept between runs, as without a state_file; the nameservers serve the IP,
+        // but with another TTL than the configured one
+        assert_eq!(
+            run_nsddns(&config, &client, &mut State::default(), false),
+            Status::Updated
+        );
+        assert_eq!(mock.requests(), vec!["dnsListRecords", "dnsUpdateRecord"]);
+        assert_eq!(mock.records("example.com")[0].ttl, Some(600));
+
+        // once the new TTL is served, the API is not asked again
+        nameserver.set_ttl(600);
+        assert_eq!(
+            run_nsddns(&config, &client, &mut State::default(), false),
+            Status::NoChange
+        );
+        assert_eq!(mock.requests().len(), 2);
+        Ok(())
+    }
 }
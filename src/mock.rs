@@ -0,0 +1,732 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{BufRead, BufReader, Write},
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    thread::{self, JoinHandle},
+};
+
+use crate::{
+    ApiFormat, NsResourceRecord, NsddnsError, ProviderConfig, ProviderKind, RRType, Result, Secret,
+};
+
+/// TTL given to records created without one
+const MOCK_DEFAULT_TTL: u32 = 3603;
+
+/// A single record held by the mock
+#[derive(Clone, Debug)]
+struct MockRecord {
+    record_id: String,
+    record_type: String,
+    host: String,
+    value: String,
+    ttl: u32,
+    distance: u32,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    api_key: String,
+    public_ip: String,
+    zones: HashMap<String, Vec<MockRecord>>,
+    next_id: u64,
+    /// Answers to send instead of handling the next calls of an operation
+    injected: HashMap<String, VecDeque<Injected>>,
+    /// Operations received, in order
+    requests: Vec<String>,
+}
+
+/// Answer injected into the mock by a test
+#[derive(Clone, Debug)]
+enum Injected {
+    /// A Namesilo reply with this code and detail
+    Reply(u32, String),
+    /// An empty HTTP response with this status
+    HttpStatus(u16),
+    /// A successful HTTP response with this body
+    Body(String),
+}
+
+impl MockState {
+    fn new_record_id(&mut self) -> String {
+        self.next_id += 1;
+        format!("mock{:04}", self.next_id)
+    }
+}
+
+/// In-memory stand-in for the Namesilo API, listening on a local port until dropped
+///
+/// The mock speaks just enough HTTP for reqwest and answers `dnsListRecords`, `dnsUpdateRecord`,
+/// `dnsAddRecord` and `dnsDeleteRecord` in Namesilo's XML (or JSON) shape. It also reports a
+/// "public" IP at `ip_url`, so a whole run can be pointed at it through `api_url` and `ip_sources`.
+pub struct MockNamesilo {
+    addr: SocketAddr,
+    state: Arc<Mutex<MockState>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MockNamesilo {
+    /// Start a mock on a free local port, accepting only `api_key`
+    pub fn start(api_key: &str) -> Result<MockNamesilo> {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .map_err(|e| NsddnsError::io("Failed to start mock Namesilo server", e))?;
+        let addr = listener
+            .local_addr()
+            .map_err(|e| NsddnsError::io("Failed to start mock Namesilo server", e))?;
+
+        let state = Arc::new(Mutex::new(MockState {
+            api_key: api_key.to_owned(),
+            public_ip: String::from("1.2.3.4"),
+            ..Default::default()
+        }));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let state = Arc::clone(&state);
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stop.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    if let Ok(stream) = stream {
+                        // a client hanging up early only affects its own request
+                        let _ = handle_connection(stream, &state);
+                    }
+                }
+            })
+        };
+
+        Ok(MockNamesilo {
+            addr,
+            state,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Base URL to use as a provider's `api_url`
+    pub fn api_url(&self) -> String {
+        format!("http://{}/api", self.addr)
+    }
+
+    /// URL of a text IP source reporting the address set with `set_public_ip`
+    pub fn ip_url(&self) -> String {
+        format!("http://{}/ip", self.addr)
+    }
+
+    /// Config of a Namesilo provider which calls the mock with `api_key`
+    pub fn provider_config(&self, api_key: &str) -> ProviderConfig {
+        ProviderConfig {
+            name: String::from("namesilo"),
+            kind: ProviderKind::Namesilo,
+            api_key: Secret::new(api_key),
+            api_format: ApiFormat::Xml,
+            api_url: self.api_url(),
+        }
+    }
+
+    /// Set the address reported at `ip_url`
+    pub fn set_public_ip(&self, ip: &str) {
+        self.lock().public_ip = ip.to_owned();
+    }
+
+    /// Add a domain without records, so API calls for it succeed
+    pub fn add_domain(&self, domain: &str) {
+        self.lock().zones.entry(domain.to_owned()).or_default();
+    }
+
+    /// Add a record to a domain and return its ID
+    ///
+    /// `host` is fully qualified, the same way Namesilo lists it.
+    pub fn add_record(&self, domain: &str, host: &str, record_type: &str, value: &str) -> String {
+        let mut state = self.lock();
+        let record_id = state.new_record_id();
+        state
+            .zones
+            .entry(domain.to_owned())
+            .or_default()
+            .push(MockRecord {
+                record_id: record_id.clone(),
+                record_type: record_type.to_owned(),
+                host: host.to_owned(),
+                value: value.to_owned(),
+                ttl: MOCK_DEFAULT_TTL,
+                distance: 0,
+            });
+        record_id
+    }
+
+    /// Records of a domain which nsddns can manage, as the mock currently holds them
+    pub fn records(&self, domain: &str) -> Vec<NsResourceRecord> {
+        let state = self.lock();
+        state
+            .zones
+            .get(domain)
+            .into_iter()
+            .flatten()
+            .filter_map(|rr| {
+                Some(NsResourceRecord {
+                    record_host: rr.host.clone(),
+                    record_type: rr.record_type.parse::<RRType>().ok()?,
+                    record_value: rr.value.clone(),
+                    record_id: rr.record_id.clone(),
+                    ttl: Some(rr.ttl),
+                    distance: Some(rr.distance),
+                })
+            })
+            .collect()
+    }
+
+    /// Answer the next call of `operation` with a reply code instead of handling it
+    ///
+    /// Calling this several times queues several replies.
+    pub fn inject_reply(&self, operation: &str, code: u32, detail: &str) {
+        self.inject(operation, Injected::Reply(code, detail.to_owned()));
+    }
+
+    /// Answer the next call of `operation` with an empty HTTP response, e.g. a 503
+    ///
+    /// Calling this several times queues several responses.
+    pub fn inject_http_status(&self, operation: &str, status: u16) {
+        self.inject(operation, Injected::HttpStatus(status));
+    }
+
+    /// Answer the next call of `operation` with a raw response body, e.g. a recorded reply
+    ///
+    /// Calling this several times queues several responses.
+    pub fn inject_body(&self, operation: &str, body: &str) {
+        self.inject(operation, Injected::Body(body.to_owned()));
+    }
+
+    fn inject(&self, operation: &str, injected: Injected) {
+        self.lock()
+            .injected
+            .entry(operation.to_owned())
+            .or_default()
+            .push_back(injected);
+    }
+
+    /// Operations received so far, in order
+    pub fn requests(&self) -> Vec<String> {
+        self.lock().requests.clone()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for MockNamesilo {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // wake the accept loop so it sees the stop flag
+        let _ = TcpStream::connect(self.addr);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Stand-in for an authoritative nameserver, answering every UDP query with one address
+///
+/// Queries for the other address family get an empty answer. Point `nameservers` at `addr` to
+/// run DNS checks (`"check": "dns"`) against it.
+pub struct MockNameserver {
+    addr: SocketAddr,
+    answer: Arc<Mutex<(IpAddr, u32)>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MockNameserver {
+    /// Start a nameserver on a free local port, answering with `ip` and a TTL of 3600
+    pub fn start(ip: &str) -> Result<MockNameserver> {
+        let socket = UdpSocket::bind("127.0.0.1:0")
+            .map_err(|e| NsddnsError::io("Failed to start mock nameserver", e))?;
+        let addr = socket
+            .local_addr()
+            .map_err(|e| NsddnsError::io("Failed to start mock nameserver", e))?;
+        let answer = Arc::new(Mutex::new((parse_ip(ip)?, 3600)));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let answer = Arc::clone(&answer);
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                let mut query = [0; 512];
+                while let Ok((len, peer)) = socket.recv_from(&mut query) {
+                    if stop.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let (ip, ttl) = *answer.lock().unwrap_or_else(|e| e.into_inner());
+                    if let Some(response) = dns_response(&query[..len], ip, ttl) {
+                        let _ = socket.send_to(&response, peer);
+                    }
+                }
+            })
+        };
+
+        Ok(MockNameserver {
+            addr,
+            answer,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Address and port to list under `nameservers`
+    pub fn addr(&self) -> String {
+        self.addr.to_string()
+    }
+
+    /// Set the address served from now on
+    pub fn set_answer(&self, ip: &str) -> Result<()> {
+        self.answer.lock().unwrap_or_else(|e| e.into_inner()).0 = parse_ip(ip)?;
+        Ok(())
+    }
+
+    /// Set the TTL served from now on
+    pub fn set_ttl(&self, ttl: u32) {
+        self.answer.lock().unwrap_or_else(|e| e.into_inner()).1 = ttl;
+    }
+}
+
+impl Drop for MockNameserver {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // wake the receive loop so it sees the stop flag
+        if let Ok(socket) = UdpSocket::bind("127.0.0.1:0") {
+            let _ = socket.send_to(&[], self.addr);
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn parse_ip(ip: &str) -> Result<IpAddr> {
+    ip.parse()
+        .map_err(|_| NsddnsError::Parse(format!("'{}' is not an IP address", ip)))
+}
+
+/// Answer a single-question query with `ip` if its family matches the query type
+fn dns_response(query: &[u8], ip: IpAddr, ttl: u32) -> Option<Vec<u8>> {
+    // header, at least a root name, type and class
+    if query.len() < 17 {
+        return None;
+    }
+    let qtype = u16::from_be_bytes([query[query.len() - 4], query[query.len() - 3]]);
+    let rdata = match ip {
+        IpAddr::V4(ip) if qtype == 1 => ip.octets().to_vec(),
+        IpAddr::V6(ip) if qtype == 28 => ip.octets().to_vec(),
+        _ => Vec::new(),
+    };
+
+    let mut response = query.to_vec();
+    // response, authoritative answer
+    response[2..4].copy_from_slice(&0x8400u16.to_be_bytes());
+    response[6..8].copy_from_slice(&u16::from(!rdata.is_empty()).to_be_bytes());
+    if !rdata.is_empty() {
+        // name pointer to the question, type, class IN, TTL, rdata
+        response.extend_from_slice(&[0xc0, 12]);
+        response.extend_from_slice(&qtype.to_be_bytes());
+        response.extend_from_slice(&[0, 1]);
+        response.extend_from_slice(&ttl.to_be_bytes());
+        response.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        response.extend_from_slice(&rdata);
+    }
+    Some(response)
+}
+
+/// Read one request from a connection and answer it
+fn handle_connection(mut stream: TcpStream, state: &Mutex<MockState>) -> std::io::Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // skip the headers, the mock only needs the request target
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header == "\r\n" || header == "\n" {
+            break;
+        }
+    }
+
+    let target = request_line.split_whitespace().nth(1).unwrap_or("/");
+    let (status, content_type, body) =
+        match reqwest::Url::parse("http://mock").and_then(|base| base.join(target)) {
+            Ok(url) => {
+                let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+                respond(&mut state, &url)
+            }
+            Err(_) => (status_line(400), "text/plain", String::from("bad request")),
+        };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// Build the (status, content type, body) answering a request
+fn respond(state: &mut MockState, url: &reqwest::Url) -> (String, &'static str, String) {
+    if url.path() == "/ip" {
+        return (status_line(200), "text/plain", state.public_ip.clone());
+    }
+    let Some(operation) = url.path().strip_prefix("/api/") else {
+        return (status_line(404), "text/plain", String::from("not found"));
+    };
+
+    let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+    let json = param(&params, "type") == "json";
+    state.requests.push(operation.to_owned());
+
+    let reply = match state
+        .injected
+        .get_mut(operation)
+        .and_then(|replies| replies.pop_front())
+    {
+        Some(Injected::Reply(code, detail)) => MockReply::error(code, &detail),
+        Some(Injected::HttpStatus(status)) => {
+            return (status_line(status), "text/plain", String::new());
+        }
+        Some(Injected::Body(body)) => {
+            let content_type = if json { "application/json" } else { "text/xml" };
+            return (status_line(200), content_type, body);
+        }
+        None if param(&params, "key") != state.api_key => MockReply::error(110, "Invalid API Key"),
+        None => handle_operation(state, operation, &params),
+    };
+
+    let content_type = if json { "application/json" } else { "text/xml" };
+    (
+        status_line(200),
+        content_type,
+        reply.render(operation, json),
+    )
+}
+
+/// Status and reason phrase of an HTTP response, e.g. "503 Service Unavailable"
+fn status_line(status: u16) -> String {
+    let reason = reqwest::StatusCode::from_u16(status)
+        .ok()
+        .and_then(|status| status.canonical_reason())
+        .unwrap_or("Unknown");
+    format!("{} {}", status, reason)
+}
+
+/// Value of a query parameter, blank if it is missing
+fn param<'a>(params: &'a HashMap<String, String>, name: &str) -> &'a str {
+    params.get(name).map(|v| v.as_str()).unwrap_or_default()
+}
+
+/// Apply an API operation to the mock's records
+fn handle_operation(
+    state: &mut MockState,
+    operation: &str,
+    params: &HashMap<String, String>,
+) -> MockReply {
+    let param = |name: &str| param(params, name);
+    let domain = param("domain").to_owned();
+    if !state.zones.contains_key(&domain) {
+        return MockReply::error(200, "Domain is not active, or does not belong to this user");
+    }
+    let host = |rrhost: &str| {
+        if rrhost.is_empty() {
+            domain.clone()
+        } else {
+            format!("{}.{}", rrhost, domain)
+        }
+    };
+
+    match operation {
+        "dnsListRecords" => MockReply {
+            records: state.zones[&domain].clone(),
+            ..MockReply::success()
+        },
+        "dnsUpdateRecord" => {
+            let new_host = host(param("rrhost"));
+            let records = state.zones.get_mut(&domain).unwrap();
+            match records.iter_mut().find(|rr| rr.record_id == param("rrid")) {
+                Some(rr) => {
+                    rr.host = new_host;
+                    rr.value = param("rrvalue").to_owned();
+                    // like Namesilo, an update without rrttl resets the TTL to the default
+                    rr.ttl = param("rrttl").parse().unwrap_or(MOCK_DEFAULT_TTL);
+                    if let Ok(distance) = param("rrdistance").parse() {
+                        rr.distance = distance;
+                    }
+                    MockReply {
+                        record_id: Some(rr.record_id.clone()),
+                        ..MockReply::success()
+                    }
+                }
+                None => MockReply::error(210, "Invalid record ID"),
+            }
+        }
+        "dnsAddRecord" => {
+            let record_id = state.new_record_id();
+            let record = MockRecord {
+                record_id: record_id.clone(),
+                record_type: param("rrtype").to_owned(),
+                host: host(param("rrhost")),
+                value: param("rrvalue").to_owned(),
+                ttl: param("rrttl").parse().unwrap_or(MOCK_DEFAULT_TTL),
+                distance: param("rrdistance").parse().unwrap_or_default(),
+            };
+            state.zones.get_mut(&domain).unwrap().push(record);
+            MockReply {
+                record_id: Some(record_id),
+                ..MockReply::success()
+            }
+        }
+        "dnsDeleteRecord" => {
+            let records = state.zones.get_mut(&domain).unwrap();
+            let before = records.len();
+            records.retain(|rr| rr.record_id != param("rrid"));
+            if records.len() < before {
+                MockReply::success()
+            } else {
+                MockReply::error(210, "Invalid record ID")
+            }
+        }
+        _ => MockReply::error(101, "Invalid API operation"),
+    }
+}
+
+/// Reply the mock sends for an operation
+#[derive(Debug)]
+struct MockReply {
+    code: u32,
+    detail: String,
+    records: Vec<MockRecord>,
+    record_id: Option<String>,
+}
+
+impl MockReply {
+    fn success() -> Self {
+        MockReply {
+            code: 300,
+            detail: String::from("success"),
+            records: Vec::new(),
+            record_id: None,
+        }
+    }
+
+    fn error(code: u32, detail: &str) -> Self {
+        MockReply {
+            code,
+            detail: detail.to_owned(),
+            ..MockReply::success()
+        }
+    }
+
+    /// Render the whole response document in Namesilo's shape
+    fn render(&self, operation: &str, json: bool) -> String {
+        if json {
+            let mut reply = json::object! { code: self.code, detail: self.detail.as_str() };
+            if !self.records.is_empty() {
+                reply["resource_record"] = self
+                    .records
+                    .iter()
+                    .map(|rr| {
+                        json::object! {
+                            record_id: rr.record_id.as_str(),
+                            type: rr.record_type.as_str(),
+                            host: rr.host.as_str(),
+                            value: rr.value.as_str(),
+                            ttl: rr.ttl,
+                            distance: rr.distance,
+                        }
+                    })
+                    .collect::<Vec<_>>()
+                    .into();
+            }
+            if let Some(record_id) = &self.record_id {
+                reply["record_id"] = record_id.as_str().into();
+            }
+            return json::object! {
+                request: { operation: operation, ip: "127.0.0.1" },
+                reply: reply,
+            }
+            .dump();
+        }
+
+        let mut xml = format!(
+            "<?xml version=\"1.0\"?>\n<namesilo><request><operation>{}</operation><ip>127.0.0.1</ip></request><reply><code>{}</code><detail>{}</detail>",
+            xml_escape(operation),
+            self.code,
+            xml_escape(&self.detail)
+        );
+        for rr in &self.records {
+            xml.push_str(&format!(
+                "<resource_record><record_id>{}</record_id><type>{}</type><host>{}</host><value>{}</value><ttl>{}</ttl><distance>{}</distance></resource_record>",
+                xml_escape(&rr.record_id),
+                xml_escape(&rr.record_type),
+                xml_escape(&rr.host),
+                xml_escape(&rr.value),
+                rr.ttl,
+                rr.distance
+            ));
+        }
+        if let Some(record_id) = &self.record_id {
+            xml.push_str(&format!("<record_id>{}</record_id>", xml_escape(record_id)));
+        }
+        xml.push_str("</reply></namesilo>");
+        xml
+    }
+}
+
+/// Escape text for use inside an XML element
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ip::get_current_ip, provider::build_provider, HttpClient, HttpConfig, IpSource};
+
+    #[test]
+    fn test_mock_round_trip() -> Result<()> {
+        let mock = MockNamesilo::start("mock-key")?;
+        let www_id = mock.add_record("example.com", "www.example.com", "A", "203.0.113.1");
+        mock.add_record("example.com", "example.com", "MX", "mx.example.net");
+        let client = HttpClient::new(&HttpConfig::default())?;
+
+        for api_format in [ApiFormat::Xml, ApiFormat::Json] {
+            let provider = build_provider(
+                &ProviderConfig {
+                    api_format,
+                    ..mock.provider_config("mock-key")
+                },
+                &client,
+            );
+            let records = provider.list_records("example.com")?;
+            assert_eq!(records.len(), 2);
+            assert_eq!(records[0].record_id, www_id);
+            assert_eq!(records[0].ttl, Some(MOCK_DEFAULT_TTL));
+            assert_eq!(records[1].record_type, RRType::MX);
+
+            provider.update_record("example.com", &records[0], "203.0.113.2")?;
+            assert_eq!(mock.records("example.com")[0].record_value, "203.0.113.2");
+
+            let vpn_id = provider.create_record(
+                "example.com",
+                &NsResourceRecord {
+                    ttl: Some(3600),
+                    ..NsResourceRecord::new("vpn.example.com", RRType::AAAA, "2001:db8::1")
+                },
+            )?;
+            let vpn = mock.records("example.com").pop().unwrap();
+            assert_eq!(vpn.record_id, vpn_id);
+            assert_eq!(vpn.record_host, "vpn.example.com");
+            assert_eq!(vpn.ttl, Some(3600));
+            // the TTL is sent along with an update, so it is kept
+            provider.update_record("example.com", &vpn, "2001:db8::2")?;
+            let vpn = mock.records("example.com").pop().unwrap();
+            assert_eq!(vpn.ttl, Some(3600));
+            provider.delete_record("example.com", &vpn)?;
+
+            // MX records are created with Namesilo's default priority unless one is given
+            provider.create_record(
+                "example.com",
+                &NsResourceRecord::new("example.com", RRType::MX, "mx2.example.net"),
+            )?;
+            let mx = mock.records("example.com").pop().unwrap();
+            assert_eq!(mx.distance, Some(10));
+            provider.update_record(
+                "example.com",
+                &NsResourceRecord {
+                    distance: Some(20),
+                    ..mx.clone()
+                },
+                "mx3.example.net",
+            )?;
+            assert_eq!(mock.records("example.com")[2].distance, Some(20));
+            provider.delete_record("example.com", &mx)?;
+            assert_eq!(mock.records("example.com").len(), 2);
+        }
+
+        mock.set_public_ip("5.6.7.8");
+        let sources = vec![IpSource::text(&mock.ip_url(), RRType::A)];
+        assert_eq!(get_current_ip(&client, &sources, RRType::A, 1)?, "5.6.7.8");
+        Ok(())
+    }
+
+    #[test]
+    fn test_mock_errors() -> Result<()> {
+        let mock = MockNamesilo::start("mock-key")?;
+        mock.add_domain("example.com");
+        let client = HttpClient::new(&HttpConfig::default())?;
+
+        let provider = build_provider(&mock.provider_config("wrong-key"), &client);
+        assert!(matches!(
+            provider.list_records("example.com"),
+            Err(NsddnsError::Api { code: 110, .. })
+        ));
+
+        let provider = build_provider(&mock.provider_config("mock-key"), &client);
+        assert!(matches!(
+            provider.list_records("example.org"),
+            Err(NsddnsError::Api { code: 200, .. })
+        ));
+
+        mock.inject_reply("dnsListRecords", 421, "Temporary error");
+        assert!(matches!(
+            provider.list_records("example.com"),
+            Err(NsddnsError::Api { code: 421, detail }) if detail == "Temporary error"
+        ));
+        assert!(provider.list_records("example.com")?.is_empty());
+        assert_eq!(mock.requests().len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mock_retries() -> Result<()> {
+        let mock = MockNamesilo::start("mock-key")?;
+        let www_id = mock.add_record("example.com", "www.example.com", "A", "203.0.113.1");
+        let client = HttpClient::new(&HttpConfig {
+            retries: 2,
+            retry_backoff: std::time::Duration::ZERO,
+            ..Default::default()
+        })?;
+        let provider = build_provider(&mock.provider_config("mock-key"), &client);
+
+        // reads are retried after server errors, until the retries run out
+        mock.inject_http_status("dnsListRecords", 503);
+        mock.inject_http_status("dnsListRecords", 502);
+        let records = provider.list_records("example.com")?;
+        assert_eq!(records[0].record_id, www_id);
+        assert_eq!(mock.requests().len(), 3);
+
+        for _ in 0..3 {
+            mock.inject_http_status("dnsListRecords", 503);
+        }
+        assert!(matches!(
+            provider.list_records("example.com"),
+            Err(NsddnsError::Transport(_))
+        ));
+        assert_eq!(mock.requests().len(), 6);
+
+        // changes are not, the server might have applied them
+        mock.inject_http_status("dnsUpdateRecord", 503);
+        assert!(provider
+            .update_record("example.com", &records[0], "203.0.113.2")
+            .is_err());
+        assert_eq!(mock.requests().len(), 7);
+        assert_eq!(mock.records("example.com")[0].record_value, "203.0.113.1");
+        Ok(())
+    }
+}
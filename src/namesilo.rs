@@ -0,0 +1,588 @@
+use std::{fmt, str::FromStr};
+
+use crate::{
+    http::RetryPolicy, provider::DnsProvider, redact::redact_url, HttpClient, NsResourceRecord,
+    NsddnsError, ProviderConfig, RRType, Result, Secret,
+};
+
+/// Version of the Namesilo public API
+const NAMESILO_API_VERSION: u8 = 1;
+
+/// Base URL of the Namesilo public API
+pub const NAMESILO_API_URL: &str = "https://www.namesilo.com/api";
+
+/// Reply code of a successful operation
+const REPLY_SUCCESS: u32 = 300;
+
+/// Reply codes of operations which succeeded, but with a warning in the detail
+///
+/// 280 ("DNS modification error") is not one of them: the record was not changed.
+const REPLY_SUCCESS_WITH_WARNING: [u32; 2] = [301, 302];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+/// Format Namesilo is asked to reply in
+pub enum ApiFormat {
+    /// `type=xml`, Namesilo's original format
+    #[default]
+    Xml,
+    /// `type=json`
+    Json,
+}
+
+impl ApiFormat {
+    /// Format as the `type` query parameter spells it
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiFormat::Xml => "xml",
+            ApiFormat::Json => "json",
+        }
+    }
+}
+
+impl fmt::Display for ApiFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ApiFormat {
+    type Err = NsddnsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "xml" => Ok(ApiFormat::Xml),
+            "json" => Ok(ApiFormat::Json),
+            _ => Err(NsddnsError::Parse(format!(
+                "unsupported Namesilo API format: {}",
+                s
+            ))),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+/// The `reply` part of a Namesilo API response, in either format
+struct Reply {
+    /// Namesilo reply code
+    code: u32,
+    /// Namesilo's explanation of the code
+    detail: String,
+    /// Every field of the reply, with XML elements converted to their JSON equivalent
+    body: json::JsonValue,
+}
+
+impl Reply {
+    /// Parse a response in the given format
+    fn parse(response: &str, format: ApiFormat) -> Result<Reply> {
+        let body = match format {
+            ApiFormat::Xml => xml_reply_body(response)?,
+            ApiFormat::Json => json_reply_body(response)?,
+        };
+
+        // JSON replies carry the code as a number, XML replies as text
+        let code = body["code"]
+            .as_u32()
+            .or_else(|| body["code"].as_str().and_then(|c| c.trim().parse().ok()))
+            .ok_or_else(|| {
+                NsddnsError::Parse(String::from("Namesilo API reply has no numeric code"))
+            })?;
+        let detail = body["detail"]
+            .as_str()
+            .unwrap_or_default()
+            .trim()
+            .to_owned();
+
+        Ok(Reply { code, detail, body })
+    }
+
+    /// Turn a reply with a non-success code into an error carrying Namesilo's explanation
+    fn check(self, operation: &str) -> Result<Reply> {
+        if self.code == REPLY_SUCCESS {
+            return Ok(self);
+        }
+        if REPLY_SUCCESS_WITH_WARNING.contains(&self.code) {
+            crate::warn!(
+                "Namesilo {} succeeded with warning {}: {}",
+                operation,
+                self.code,
+                self.detail
+            );
+            return Ok(self);
+        }
+        Err(NsddnsError::Api {
+            code: self.code,
+            detail: self.detail,
+        })
+    }
+}
+
+/// The `reply` element of an XML response, converted to JSON
+fn xml_reply_body(response_xml: &str) -> Result<json::JsonValue> {
+    let api_response = roxmltree::Document::parse(response_xml)?;
+    let reply = api_response
+        .descendants()
+        .find(|n| n.has_tag_name("reply"))
+        .ok_or_else(|| NsddnsError::Parse(String::from("Namesilo API response has no reply")))?;
+    Ok(xml_to_json(reply))
+}
+
+/// Convert an XML element to JSON: text-only elements become strings, others objects, and
+/// repeated child elements become lists
+fn xml_to_json(node: roxmltree::Node) -> json::JsonValue {
+    if !node.children().any(|n| n.is_element()) {
+        return node.text().unwrap_or_default().into();
+    }
+
+    let mut object = json::JsonValue::new_object();
+    for child in node.children().filter(|n| n.is_element()) {
+        let name = child.tag_name().name();
+        let value = xml_to_json(child);
+        if !object.has_key(name) {
+            object[name] = value;
+        } else if object[name].is_array() {
+            let _ = object[name].push(value);
+        } else {
+            let first = object.remove(name);
+            object[name] = json::array![first, value];
+        }
+    }
+    object
+}
+
+/// The `reply` object of a JSON response
+fn json_reply_body(response_json: &str) -> Result<json::JsonValue> {
+    let mut api_response = json::parse(response_json)
+        .map_err(|e| NsddnsError::Parse(format!("Namesilo API response is not JSON: {}", e)))?;
+    if !api_response["reply"].is_object() {
+        return Err(NsddnsError::Parse(String::from(
+            "Namesilo API response has no reply",
+        )));
+    }
+    Ok(api_response.remove("reply"))
+}
+
+#[derive(Clone, Debug)]
+/// DNS provider backed by the Namesilo public API
+pub struct NamesiloProvider {
+    /// Client the API is called through
+    client: HttpClient,
+    /// Base URL of the API
+    api_url: String,
+    /// Namesilo API key for reading/mutating records
+    api_key: Secret,
+    /// Format Namesilo is asked to reply in
+    format: ApiFormat,
+}
+
+impl NamesiloProvider {
+    /// Create a provider for an API key with the default settings, for use as a library
+    pub fn with_api_key(api_key: &str) -> Result<Self> {
+        let config = ProviderConfig {
+            name: String::from("namesilo"),
+            kind: crate::ProviderKind::Namesilo,
+            api_key: Secret::new(api_key),
+            api_format: ApiFormat::default(),
+            api_url: NAMESILO_API_URL.to_owned(),
+        };
+        Ok(NamesiloProvider::new(
+            &config,
+            &HttpClient::new(&Default::default())?,
+        ))
+    }
+
+    /// Create a provider for a provider config, calling the API through `client`
+    pub fn new(config: &ProviderConfig, client: &HttpClient) -> Self {
+        NamesiloProvider {
+            client: client.clone(),
+            api_url: config.api_url.clone(),
+            api_key: config.api_key.clone(),
+            format: config.api_format,
+        }
+    }
+
+    /// Call a Namesilo API operation for a domain and return its successful reply
+    fn call(
+        &self,
+        operation: &str,
+        domain: &str,
+        params: &[(&str, &str)],
+        retry: RetryPolicy,
+    ) -> Result<Reply> {
+        let request = self
+            .client
+            .get(&format!("{}/{}", self.api_url, operation))
+            .query(&[("version", NAMESILO_API_VERSION)])
+            .query(&[
+                ("type", self.format.as_str()),
+                ("key", self.api_key.expose()),
+                ("domain", domain),
+            ])
+            .query(params)
+            .build()?;
+        let mut logged_url = request.url().clone();
+        redact_url(&mut logged_url);
+        crate::debug!("GET {}", logged_url);
+
+        let response = self.client.execute(request, retry)?;
+        crate::debug!("Namesilo answered {} to {}", response.status(), operation);
+        let response_body = response.error_for_status()?.text()?;
+
+        let reply = Reply::parse(&response_body, self.format)?;
+        crate::debug!(
+            "Namesilo {} reply {}: {}",
+            operation,
+            reply.code,
+            reply.detail
+        );
+        reply.check(operation)
+    }
+}
+
+impl DnsProvider for NamesiloProvider {
+    fn list_records(&self, domain: &str) -> Result<Vec<NsResourceRecord>> {
+        let reply = self.call("dnsListRecords", domain, &[], RetryPolicy::Idempotent)?;
+        let resource_records = parse_records(&reply.body);
+        for rr in &resource_records {
+            crate::debug!("{} record in {}: {:?}", rr.record_type, domain, rr);
+        }
+        Ok(resource_records)
+    }
+
+    fn update_record(
+        &self,
+        domain: &str,
+        resource_record: &NsResourceRecord,
+        new_value: &str,
+    ) -> Result<()> {
+        let distance = record_distance(resource_record);
+        // without rrttl, Namesilo resets the record to its default TTL
+        let ttl = resource_record.ttl.map(|ttl| ttl.to_string());
+        let mut params = vec![
+            ("rrhost", resource_record.subdomain(domain)),
+            ("rrvalue", new_value),
+            ("rrid", resource_record.record_id.as_str()),
+        ];
+        if let Some(distance) = &distance {
+            params.push(("rrdistance", distance));
+        }
+        if let Some(ttl) = &ttl {
+            params.push(("rrttl", ttl));
+        }
+        self.call(
+            "dnsUpdateRecord",
+            domain,
+            &params,
+            RetryPolicy::NotIdempotent,
+        )?;
+        Ok(())
+    }
+
+    fn create_record(&self, domain: &str, resource_record: &NsResourceRecord) -> Result<String> {
+        let distance = record_distance(resource_record);
+        let ttl = resource_record.ttl.map(|ttl| ttl.to_string());
+        let mut params = vec![
+            ("rrtype", resource_record.record_type.as_str()),
+            ("rrhost", resource_record.subdomain(domain)),
+            ("rrvalue", resource_record.record_value.as_str()),
+        ];
+        if let Some(distance) = &distance {
+            params.push(("rrdistance", distance));
+        }
+        if let Some(ttl) = &ttl {
+            params.push(("rrttl", ttl));
+        }
+        let reply = self.call("dnsAddRecord", domain, &params, RetryPolicy::NotIdempotent)?;
+        parse_record_id(&reply.body)
+    }
+
+    fn delete_record(&self, domain: &str, resource_record: &NsResourceRecord) -> Result<()> {
+        self.call(
+            "dnsDeleteRecord",
+            domain,
+            &[("rrid", resource_record.record_id.as_str())],
+            RetryPolicy::NotIdempotent,
+        )?;
+        Ok(())
+    }
+}
+
+/// Collect the resource records of a dnsListRecords reply
+///
+/// Records of types nsddns does not know (such as NS) are left out.
+///
+/// Records which are incomplete are skipped with a warning, so one bad record does not stop the
+/// others from being managed.
+fn parse_records(reply_body: &json::JsonValue) -> Vec<NsResourceRecord> {
+    // a single record is not wrapped in a list
+    let rrs: Vec<&json::JsonValue> = match &reply_body["resource_record"] {
+        json::JsonValue::Array(rrs) => rrs.iter().collect(),
+        json::JsonValue::Null => Vec::new(),
+        rr => vec![rr],
+    };
+
+    let mut resource_records = Vec::new();
+    for (i, rr) in rrs.into_iter().enumerate() {
+        let record_type = match text_field(rr, "type").map(|t| t.parse::<RRType>()) {
+            Some(Ok(record_type)) => record_type,
+            Some(Err(_)) => continue,
+            None => {
+                crate::warn!("skipping Namesilo resource record #{} without a type", i);
+                continue;
+            }
+        };
+
+        match parse_record(rr, record_type) {
+            Ok(resource_record) => resource_records.push(resource_record),
+            Err(e) => crate::warn!("skipping Namesilo resource record #{}: {}", i, e),
+        }
+    }
+
+    resource_records
+}
+
+/// Parse a single resource record of a known type
+fn parse_record(rr: &json::JsonValue, record_type: RRType) -> Result<NsResourceRecord> {
+    let required = |name: &str| {
+        text_field(rr, name)
+            .ok_or_else(|| NsddnsError::Parse(format!("{} record has no {}", record_type, name)))
+    };
+    let record_host = required("host")?;
+    let record_value = required("value")?;
+    let record_id = required("record_id")?;
+
+    // a bad ttl or distance is only informational, the record is still usable
+    let optional_number = |name: &str| {
+        let text = text_field(rr, name)?;
+        match text.parse::<u32>() {
+            Ok(number) => Some(number),
+            Err(_) => {
+                crate::warn!(
+                    "ignoring invalid {} '{}' of {} record {}",
+                    name,
+                    text,
+                    record_type,
+                    record_host
+                );
+                None
+            }
+        }
+    };
+
+    Ok(NsResourceRecord {
+        ttl: optional_number("ttl"),
+        distance: optional_number("distance"),
+        record_host,
+        record_type,
+        record_value,
+        record_id,
+    })
+}
+
+/// Non-empty text of a reply field, which JSON replies may also send as a number
+fn text_field(object: &json::JsonValue, name: &str) -> Option<String> {
+    let text = match &object[name] {
+        json::JsonValue::Number(number) => number.to_string(),
+        value => value.as_str()?.trim().to_owned(),
+    };
+    if text.is_empty() {
+        None
+    } else {
+        Some(text)
+    }
+}
+
+/// The `rrdistance` to send for a record, which only MX and SRV records have
+fn record_distance(resource_record: &NsResourceRecord) -> Option<String> {
+    if !resource_record.record_type.has_distance() {
+        return None;
+    }
+    // Namesilo requires a distance for these types, 10 is its own default
+    Some(resource_record.distance.unwrap_or(10).to_string())
+}
+
+/// Parse the ID of a newly created record from a dnsAddRecord reply
+fn parse_record_id(reply_body: &json::JsonValue) -> Result<String> {
+    text_field(reply_body, "record_id").ok_or_else(|| {
+        NsddnsError::Parse(String::from(
+            "Namesilo API response did not include a record_id",
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Read a recorded Namesilo response from tests/fixtures/namesilo
+    fn fixture(name: &str) -> String {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/namesilo")
+            .join(name);
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    /// Parse the records of a recorded dnsListRecords response
+    fn fixture_records(name: &str, format: ApiFormat) -> Result<Vec<NsResourceRecord>> {
+        let reply = Reply::parse(&fixture(name), format)?.check("dnsListRecords")?;
+        Ok(parse_records(&reply.body))
+    }
+
+    #[test]
+    fn test_fixture_list_records() -> Result<()> {
+        for (name, format) in [
+            ("list_records.xml", ApiFormat::Xml),
+            ("list_records.json", ApiFormat::Json),
+        ] {
+            let res = fixture_records(name, format)?;
+            assert_eq!(res.len(), 5, "{}", name);
+            assert_eq!(res[0].record_host, "example.com");
+            assert_eq!(res[0].record_value, "203.0.113.7");
+            assert_eq!(res[0].ttl, Some(3603));
+            assert_eq!(res[0].distance, Some(0));
+            assert_eq!(res[2].record_type, RRType::AAAA);
+            assert_eq!(res[2].record_id, "1b2c3d4e5f60718293a4b5c6d7e8f901");
+            assert_eq!(res[3].record_type, RRType::MX);
+            assert_eq!(res[3].distance, Some(10));
+            assert_eq!(res[4].record_type, RRType::TXT);
+            assert_eq!(res[4].record_value, "v=spf1 include:_spf.example.net ~all");
+        }
+
+        let res = fixture_records("list_records_single.json", ApiFormat::Json)?;
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].ttl, Some(3603));
+
+        assert!(fixture_records("list_records_empty.xml", ApiFormat::Xml)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_fixture_list_records_partial() -> Result<()> {
+        // the record without a value and the one without an ID are skipped
+        let res = fixture_records("list_records_partial.xml", ApiFormat::Xml)?;
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].record_host, "www.example.com");
+        assert_eq!(res[0].ttl, None);
+
+        assert!(matches!(
+            fixture_records("list_records_truncated.xml", ApiFormat::Xml),
+            Err(NsddnsError::Parse(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_fixture_error_replies() -> Result<()> {
+        for (name, format) in [
+            ("invalid_key.xml", ApiFormat::Xml),
+            ("invalid_key.json", ApiFormat::Json),
+        ] {
+            assert!(matches!(
+                fixture_records(name, format),
+                Err(NsddnsError::Api { code: 110, detail }) if detail == "Invalid API Key"
+            ));
+        }
+        assert!(matches!(
+            fixture_records("domain_not_owned.xml", ApiFormat::Xml),
+            Err(NsddnsError::Api { code: 200, .. })
+        ));
+
+        let reply = Reply::parse(&fixture("update_record_failed.xml"), ApiFormat::Xml)?;
+        assert!(matches!(
+            reply.check("dnsUpdateRecord"),
+            Err(NsddnsError::Api { code: 280, detail })
+                if detail == "Resource record already exists with these values"
+        ));
+
+        let reply = Reply::parse(&fixture("add_record.xml"), ApiFormat::Xml)?;
+        assert_eq!(
+            parse_record_id(&reply.check("dnsAddRecord")?.body)?,
+            "4e5f60718293a4b5c6d7e8f901122334"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_xml_no_results() -> Result<()> {
+        let xml_data = String::from("<namesilo><reply><resource_record><record_id>a1234</record_id><type>NS</type><host>hooo</host><value>woooo</value></resource_record></reply></namesilo>");
+        let res = parse_records(&xml_reply_body(&xml_data)?);
+        assert!(res.is_empty());
+
+        let xml_data = String::from("<namesilo><reply><resource_record><record_id>a1234</record_id><type>CNAME</type><host>hooo</host><value>woooo</value></resource_record></reply></namesilo>");
+        let res = parse_records(&xml_reply_body(&xml_data)?);
+        assert_eq!(res[0].record_type, RRType::CNAME);
+        assert_eq!(res[0].record_value, "woooo");
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_xml_one_record() -> Result<()> {
+        let xml_data = String::from("<namesilo><reply><resource_record><record_id>a1234</record_id><type>A</type><host>rob</host><value>1234</value></resource_record></reply></namesilo>");
+        let res = parse_records(&xml_reply_body(&xml_data)?);
+        assert!(res.len() == 1);
+
+        let rr = res.first().unwrap();
+        assert_eq!(rr.record_id, "a1234");
+        assert_eq!(rr.record_host, "rob");
+        assert_eq!(rr.record_value, "1234");
+        assert_eq!(rr.record_type, RRType::A);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_xml_aaaa_record() -> Result<()> {
+        let xml_data = String::from("<namesilo><reply><resource_record><record_id>a1234</record_id><type>A</type><host>rob</host><value>1234</value></resource_record><resource_record><record_id>b5678</record_id><type>AAAA</type><host>rob</host><value>2001:db8::1</value></resource_record></reply></namesilo>");
+        let res = parse_records(&xml_reply_body(&xml_data)?);
+        assert!(res.len() == 2);
+
+        let rr = &res[1];
+        assert_eq!(rr.record_id, "b5678");
+        assert_eq!(rr.record_type, RRType::AAAA);
+        assert_eq!(rr.record_value, "2001:db8::1");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_add_record_reply() -> Result<()> {
+        let xml_data = "<namesilo><request><operation>dnsAddRecord</operation><ip>1.2.3.4</ip></request><reply><code>300</code><detail>success</detail><record_id>c9012</record_id></reply></namesilo>";
+        let reply = Reply::parse(xml_data, ApiFormat::Xml)?.check("dnsAddRecord")?;
+        assert_eq!(parse_record_id(&reply.body)?, "c9012");
+
+        let xml_data =
+            "<namesilo><reply><code>110</code><detail>Invalid API Key</detail></reply></namesilo>";
+        assert!(matches!(
+            Reply::parse(xml_data, ApiFormat::Xml)?.check("dnsAddRecord"),
+            Err(NsddnsError::Api { code: 110, detail }) if detail == "Invalid API Key"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_json_reply() -> Result<()> {
+        let json_data = r#"{"request": {"operation": "dnsListRecords", "ip": "1.2.3.4"}, "reply": {"code": 300, "detail": "success", "resource_record": [{"record_id": "a1234", "type": "A", "host": "rob.example.com", "value": "1.2.3.4", "ttl": 7207, "distance": 0}, {"record_id": "b5678", "type": "MX", "host": "example.com", "value": "mx.example.com", "ttl": 7207, "distance": 10}]}}"#;
+        let reply = Reply::parse(json_data, ApiFormat::Json)?.check("dnsListRecords")?;
+        let res = parse_records(&reply.body);
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[0].record_id, "a1234");
+        assert_eq!(res[0].record_value, "1.2.3.4");
+        assert_eq!(res[1].record_type, RRType::MX);
+        assert_eq!(res[1].distance, Some(10));
+
+        let json_data = r#"{"reply": {"code": 302, "detail": "contact information error"}}"#;
+        assert_eq!(
+            Reply::parse(json_data, ApiFormat::Json)?
+                .check("dnsAddRecord")?
+                .detail,
+            "contact information error"
+        );
+
+        let json_data = r#"{"reply": {"code": "280", "detail": "record already exists"}}"#;
+        assert!(matches!(
+            Reply::parse(json_data, ApiFormat::Json)?.check("dnsAddRecord"),
+            Err(NsddnsError::Api { code: 280, .. })
+        ));
+        assert!(Reply::parse(r#"{"reply": {}}"#, ApiFormat::Json).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,38 @@
+use crate::{
+    namesilo::NamesiloProvider, HttpClient, NsResourceRecord, ProviderConfig, ProviderKind, Result,
+};
+
+/// Operations nsddns needs from a DNS hosting backend
+///
+/// Records are always addressed by the domain (zone) they live in, and hosts are reported fully
+/// qualified, the same way Namesilo reports them.
+pub trait DnsProvider {
+    /// List the resource records of a domain
+    fn list_records(&self, domain: &str) -> Result<Vec<NsResourceRecord>>;
+
+    /// Update an existing resource record to a new value
+    ///
+    /// The record's `ttl` and `distance` are sent along if set, so they are kept (or changed) rather
+    /// than reset to the provider's defaults.
+    fn update_record(
+        &self,
+        domain: &str,
+        resource_record: &NsResourceRecord,
+        new_value: &str,
+    ) -> Result<()>;
+
+    /// Create a new resource record and return the provider's ID for it
+    ///
+    /// The record's `record_id` is ignored, and its `ttl` and `distance` are only sent if set.
+    fn create_record(&self, domain: &str, resource_record: &NsResourceRecord) -> Result<String>;
+
+    /// Delete an existing resource record
+    fn delete_record(&self, domain: &str, resource_record: &NsResourceRecord) -> Result<()>;
+}
+
+/// Build the DNS provider described by a provider config
+pub fn build_provider(config: &ProviderConfig, client: &HttpClient) -> Box<dyn DnsProvider> {
+    match config.kind {
+        ProviderKind::Namesilo => Box::new(NamesiloProvider::new(config, client)),
+    }
+}
@@ -0,0 +1,23 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+/// Random duration between zero and `max` (inclusive)
+pub(crate) fn random_duration(max: Duration) -> Duration {
+    let max_millis = u64::try_from(max.as_millis()).unwrap_or(u64::MAX);
+    if max_millis == 0 {
+        return Duration::ZERO;
+    }
+
+    Duration::from_millis(random_u64() % max_millis.saturating_add(1))
+}
+
+/// Random number, good enough for spreading out requests but not for anything secret
+pub(crate) fn random_u64() -> u64 {
+    // RandomState is seeded from the OS and differs between calls
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u8(0);
+    hasher.finish()
+}
@@ -0,0 +1,139 @@
+use std::{
+    borrow::Cow,
+    fmt,
+    sync::{Mutex, OnceLock},
+};
+
+/// Text shown in place of a secret
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Query parameters which carry credentials and are redacted from URLs
+const SECRET_QUERY_PARAMS: [&str; 1] = ["key"];
+
+/// Shortest secret scrubbed from log lines; shorter ones would mangle unrelated text
+const MIN_SCRUBBED_LEN: usize = 8;
+
+/// Every API key created so far, scrubbed from log lines as a last line of defense
+static KNOWN_SECRETS: OnceLock<Mutex<Vec<String>>> = OnceLock::new();
+
+#[derive(Clone, PartialEq, Eq)]
+/// A credential which is never shown by `Debug` or `Display`
+///
+/// The value is only available through `expose`, which should only be used to hand it to the
+/// provider's API.
+pub struct Secret(String);
+
+impl Secret {
+    /// Wrap an API key, remembering it so it can be scrubbed from log output
+    ///
+    /// Values shorter than 8 characters are not scrubbed, as they could turn up in record
+    /// values, IPs or any other text.
+    pub fn new(value: impl Into<String>) -> Self {
+        let value = value.into();
+        if value.len() >= MIN_SCRUBBED_LEN {
+            let mut known = known_secrets().lock().unwrap_or_else(|e| e.into_inner());
+            if !known.contains(&value) {
+                known.push(value.clone());
+            }
+        }
+        Secret(value)
+    }
+
+    /// Wrap a credential which is never scrubbed from log output, such as a user's password
+    pub fn unlisted(value: impl Into<String>) -> Self {
+        Secret(value.into())
+    }
+
+    /// The credential itself
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+fn known_secrets() -> &'static Mutex<Vec<String>> {
+    KNOWN_SECRETS.get_or_init(|| Mutex::new(Vec::new()))
+}
+
+/// Replace the values of credential query parameters in a URL
+pub fn redact_url(url: &mut reqwest::Url) {
+    if !url
+        .query_pairs()
+        .any(|(k, _)| SECRET_QUERY_PARAMS.contains(&k.as_ref()))
+    {
+        return;
+    }
+
+    let pairs: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+    url.query_pairs_mut()
+        .clear()
+        .extend_pairs(pairs.iter().map(|(k, v)| {
+            if SECRET_QUERY_PARAMS.contains(&k.as_str()) {
+                (k.as_str(), REDACTED)
+            } else {
+                (k.as_str(), v.as_str())
+            }
+        }));
+}
+
+/// Replace every known secret in a piece of text
+pub fn redact_text(text: &str) -> Cow<'_, str> {
+    let known = known_secrets().lock().unwrap_or_else(|e| e.into_inner());
+    let mut text = Cow::Borrowed(text);
+    for secret in known.iter() {
+        if text.contains(secret.as_str()) {
+            text = Cow::Owned(text.replace(secret.as_str(), REDACTED));
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret_is_not_shown() {
+        let secret = Secret::new("0123456789abcdef");
+        assert_eq!(format!("{:?}", secret), REDACTED);
+        assert_eq!(secret.to_string(), REDACTED);
+        assert_eq!(secret.expose(), "0123456789abcdef");
+        assert_eq!(
+            redact_text("GET /api?key=0123456789abcdef&domain=x"),
+            "GET /api?key=[REDACTED]&domain=x"
+        );
+    }
+
+    #[test]
+    fn test_short_and_unlisted_secrets_are_not_scrubbed() {
+        let short = Secret::new("123");
+        let password = Secret::unlisted("password-for-router");
+        assert_eq!(format!("{:?}", short), REDACTED);
+        assert_eq!(password.to_string(), REDACTED);
+        assert_eq!(redact_text("updated to 1.2.3.4"), "updated to 1.2.3.4");
+        assert_eq!(redact_text("password-for-router"), "password-for-router");
+    }
+
+    #[test]
+    fn test_redact_url() {
+        let mut url = reqwest::Url::parse(
+            "https://www.namesilo.com/api/dnsListRecords?version=1&key=abc&domain=example.com",
+        )
+        .unwrap();
+        redact_url(&mut url);
+        assert!(!url.as_str().contains("abc"));
+        assert!(url.as_str().contains("version=1"));
+        assert!(url.as_str().contains("domain=example.com"));
+    }
+}
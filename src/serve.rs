@@ -0,0 +1,603 @@
+use std::{
+    fmt,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{ip::validate_ip, redact::Secret, NsddnsError, RRType, Result};
+
+/// Address the update server listens on unless configured otherwise
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:8245";
+
+/// Path dyndns2 clients send updates to
+const UPDATE_PATH: &str = "/nic/update";
+
+/// Time a client may take to send its whole request
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest request line or header accepted from a client
+const MAX_LINE_LENGTH: u64 = 8192;
+
+/// Most headers accepted in one request
+const MAX_HEADERS: usize = 64;
+
+/// Most bytes of headers accepted in one request
+const MAX_HEADER_BYTES: usize = 16384;
+
+/// How often the accept loop checks whether it has been asked to stop
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Settings of the dyndns2 update server
+pub struct ServeConfig {
+    /// Address and port to listen on
+    pub listen: String,
+    /// Clients allowed to send updates
+    pub clients: Vec<ServeClient>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Credentials of a dyndns2 client and the hosts it may update
+pub struct ServeClient {
+    /// User name sent through HTTP basic auth
+    pub username: String,
+    /// Password sent through HTTP basic auth
+    pub password: Secret,
+    /// Fully qualified hosts the client may update, each of which has to be a configured record
+    pub hosts: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Answer to one host of an update request, in the dyndns2 protocol's words
+pub enum UpdateReply {
+    /// The record was changed to the address
+    Good(String),
+    /// The record already held the address
+    NoChg(String),
+    /// The credentials are missing or wrong
+    BadAuth,
+    /// The host is not one the client may update, or has no record of the address's type
+    NoHost,
+    /// No host name was given
+    NotFqdn,
+    /// The address is not a public one, or the provider could not be read or updated
+    DnsErr,
+    /// The request could not be handled, e.g. because `myip` is not an address
+    ServerError,
+}
+
+impl fmt::Display for UpdateReply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpdateReply::Good(ip) => write!(f, "good {}", ip),
+            UpdateReply::NoChg(ip) => write!(f, "nochg {}", ip),
+            UpdateReply::BadAuth => f.write_str("badauth"),
+            UpdateReply::NoHost => f.write_str("nohost"),
+            UpdateReply::NotFqdn => f.write_str("notfqdn"),
+            UpdateReply::DnsErr => f.write_str("dnserr"),
+            UpdateReply::ServerError => f.write_str("911"),
+        }
+    }
+}
+
+/// Accept dyndns2 update requests until `stop` is set
+///
+/// Requests are handled one at a time. `update` is called for every authorized host with the
+/// record type and address to set, and decides the reply for that host.
+pub fn serve<F>(
+    listener: &TcpListener,
+    config: &ServeConfig,
+    stop: &AtomicBool,
+    mut update: F,
+) -> Result<()>
+where
+    F: FnMut(&str, RRType, &str) -> UpdateReply,
+{
+    // polling lets the loop notice the stop flag, a blocking accept would not return on a signal
+    listener
+        .set_nonblocking(true)
+        .map_err(|e| NsddnsError::io("Failed to set up the update server", e))?;
+
+    while !stop.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                if let Err(e) = handle_connection(stream, peer, config, &mut update) {
+                    crate::debug!("connection from {} failed: {}", peer, e);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL_INTERVAL),
+            Err(e) => crate::warn!("failed to accept a connection: {}", e),
+        }
+    }
+    Ok(())
+}
+
+/// Read one request from a connection and answer it
+fn handle_connection<F>(
+    stream: TcpStream,
+    peer: SocketAddr,
+    config: &ServeConfig,
+    update: &mut F,
+) -> io::Result<()>
+where
+    F: FnMut(&str, RRType, &str) -> UpdateReply,
+{
+    stream.set_nonblocking(false)?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+    // one deadline for the whole request, so a client trickling bytes cannot hold the server
+    let mut reader = BufReader::new(DeadlineReader {
+        stream: &stream,
+        deadline: Instant::now() + CLIENT_TIMEOUT,
+    });
+    let (status, body) = match read_head(&mut reader)? {
+        Ok((target, authorization)) => {
+            handle_request(config, &target, authorization.as_deref(), peer.ip(), update)
+        }
+        Err(status) => {
+            crate::debug!("rejected request from {} with status {}", peer, status);
+            (status, String::from("bad request"))
+        }
+    };
+    write_response(&stream, status, &body)
+}
+
+/// Reader of a client connection which fails once the request's deadline has passed
+struct DeadlineReader<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "client took too long to send its request",
+            ));
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.read(buf)
+    }
+}
+
+/// Read the request line and headers, returning the request target and `Authorization` header
+///
+/// A request whose head is too large is answered with the status in the inner error instead.
+fn read_head<R: BufRead>(
+    reader: &mut R,
+) -> io::Result<std::result::Result<(String, Option<String>), u16>> {
+    let mut request_line = String::new();
+    reader
+        .by_ref()
+        .take(MAX_LINE_LENGTH)
+        .read_line(&mut request_line)?;
+    if !request_line.ends_with('\n') {
+        return Ok(Err(400));
+    }
+
+    let mut authorization = None;
+    let mut headers = 0;
+    let mut header_bytes = 0;
+    loop {
+        let mut header = String::new();
+        let len = reader
+            .by_ref()
+            .take(MAX_LINE_LENGTH)
+            .read_line(&mut header)?;
+        if len == 0 || header.trim().is_empty() {
+            break;
+        }
+        headers += 1;
+        header_bytes += len;
+        if !header.ends_with('\n') || headers > MAX_HEADERS || header_bytes > MAX_HEADER_BYTES {
+            return Ok(Err(431));
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("authorization") {
+                authorization = Some(value.trim().to_owned());
+            }
+        }
+    }
+
+    let target = request_line.split_whitespace().nth(1).unwrap_or("/");
+    Ok(Ok((target.to_owned(), authorization)))
+}
+
+/// Send the response to a request and close the connection
+fn write_response(mut stream: &TcpStream, status: u16, body: &str) -> io::Result<()> {
+    let challenge = if status == 401 {
+        "WWW-Authenticate: Basic realm=\"nsddns\"\r\n"
+    } else {
+        ""
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\n{}Content-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        match status {
+            200 => "200 OK",
+            400 => "400 Bad Request",
+            401 => "401 Unauthorized",
+            431 => "431 Request Header Fields Too Large",
+            _ => "404 Not Found",
+        },
+        challenge,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// Work out the (HTTP status, body) answering a request for `target`
+///
+/// The body holds one reply line per host, in the order the hosts were given.
+fn handle_request<F>(
+    config: &ServeConfig,
+    target: &str,
+    authorization: Option<&str>,
+    peer: IpAddr,
+    update: &mut F,
+) -> (u16, String)
+where
+    F: FnMut(&str, RRType, &str) -> UpdateReply,
+{
+    let Ok(url) = reqwest::Url::parse("http://nsddns").and_then(|base| base.join(target)) else {
+        return (404, String::from("not found"));
+    };
+    if url.path() != UPDATE_PATH {
+        return (404, String::from("not found"));
+    }
+
+    let Some(client) = authorization.and_then(|auth| authenticate(config, auth)) else {
+        crate::warn!("rejected update request from {}: bad credentials", peer);
+        return (401, UpdateReply::BadAuth.to_string());
+    };
+
+    let mut hostnames = String::new();
+    let mut myip = None;
+    for (key, value) in url.query_pairs() {
+        match key.as_ref() {
+            "hostname" => hostnames = value.into_owned(),
+            "myip" => myip = Some(value.into_owned()),
+            _ => {}
+        }
+    }
+
+    // without myip, the client wants the address it connected from
+    let ip = match myip.filter(|ip| !ip.is_empty()) {
+        Some(ip) => match ip.parse::<IpAddr>() {
+            Ok(ip) => ip,
+            Err(_) => {
+                crate::warn!("update request from {} has invalid myip {}", peer, ip);
+                return (200, UpdateReply::ServerError.to_string());
+            }
+        },
+        // IPv4 clients of a dual-stack socket show up as IPv4-mapped IPv6 addresses
+        None => peer.to_canonical(),
+    };
+    let record_type = match ip {
+        IpAddr::V4(_) => RRType::A,
+        IpAddr::V6(_) => RRType::AAAA,
+    };
+    // the same rules as for every other IP source, so no client can publish a private address
+    if validate_ip(&ip.to_string(), record_type).is_err() {
+        crate::warn!(
+            "rejected update request from {}: {} is not a public address",
+            peer,
+            ip
+        );
+        return (200, UpdateReply::DnsErr.to_string());
+    }
+
+    let hosts: Vec<String> = hostnames
+        .split(',')
+        .map(|host| host.trim().trim_end_matches('.').to_ascii_lowercase())
+        .filter(|host| !host.is_empty())
+        .collect();
+    if hosts.is_empty() {
+        return (200, UpdateReply::NotFqdn.to_string());
+    }
+
+    let replies: Vec<String> = hosts
+        .iter()
+        .map(|host| {
+            let reply = if client.hosts.contains(host) {
+                update(host, record_type, &ip.to_string())
+            } else {
+                UpdateReply::NoHost
+            };
+            crate::info!(
+                "update request from {} ({}) for {}: {}",
+                client.username,
+                peer,
+                host,
+                reply
+            );
+            reply.to_string()
+        })
+        .collect();
+    (200, replies.join("\n"))
+}
+
+/// Find the client whose credentials an `Authorization` header carries
+fn authenticate<'a>(config: &'a ServeConfig, authorization: &str) -> Option<&'a ServeClient> {
+    let (scheme, credentials) = authorization.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let credentials = String::from_utf8(decode_base64(credentials.trim())?).ok()?;
+    let (username, password) = credentials.split_once(':')?;
+
+    config.clients.iter().find(|client| {
+        client.username == username
+            && constant_time_eq(client.password.expose().as_bytes(), password.as_bytes())
+    })
+}
+
+/// Compare two byte strings in a time which does not depend on where they differ
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Decode standard base64, as used by HTTP basic auth
+fn decode_base64(encoded: &str) -> Option<Vec<u8>> {
+    let value = |c: u8| -> Option<u32> {
+        match c {
+            b'A'..=b'Z' => Some((c - b'A') as u32),
+            b'a'..=b'z' => Some((c - b'a' + 26) as u32),
+            b'0'..=b'9' => Some((c - b'0' + 52) as u32),
+            b'+' => Some(62),
+            b'/' => Some(63),
+            _ => None,
+        }
+    };
+
+    let encoded = encoded.trim_end_matches('=').as_bytes();
+    let mut decoded = Vec::with_capacity(encoded.len() * 3 / 4);
+    for chunk in encoded.chunks(4) {
+        if chunk.len() == 1 {
+            return None;
+        }
+        let mut bits = 0;
+        for (i, &c) in chunk.iter().enumerate() {
+            bits |= value(c)? << (18 - 6 * i);
+        }
+        decoded.extend_from_slice(&bits.to_be_bytes()[1..chunk.len()]);
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config() -> ServeConfig {
+        ServeConfig {
+            listen: DEFAULT_LISTEN.to_owned(),
+            clients: vec![ServeClient {
+                username: String::from("router"),
+                password: Secret::unlisted("hunter2"),
+                hosts: vec![
+                    String::from("home.example.com"),
+                    String::from("vpn.example.com"),
+                ],
+            }],
+        }
+    }
+
+    #[test]
+    fn test_decode_base64() {
+        assert_eq!(
+            decode_base64("cm91dGVyOmh1bnRlcjI=").as_deref(),
+            Some(&b"router:hunter2"[..])
+        );
+        assert_eq!(decode_base64("YQ==").as_deref(), Some(&b"a"[..]));
+        assert_eq!(decode_base64("YWJj").as_deref(), Some(&b"abc"[..]));
+        assert_eq!(decode_base64("Y*=="), None);
+    }
+
+    #[test]
+    fn test_handle_request() {
+        let config = test_config();
+        let auth = Some("Basic cm91dGVyOmh1bnRlcjI=");
+        let peer: IpAddr = "5.6.7.8".parse().unwrap();
+        let mut updates = Vec::new();
+        let mut update = |host: &str, record_type: RRType, ip: &str| {
+            updates.push((host.to_owned(), record_type, ip.to_owned()));
+            if host == "vpn.example.com" {
+                UpdateReply::NoChg(ip.to_owned())
+            } else {
+                UpdateReply::Good(ip.to_owned())
+            }
+        };
+
+        assert_eq!(
+            handle_request(
+                &config,
+                "/nic/update?hostname=home.example.com,vpn.example.com,other.example.com&myip=1.2.3.4",
+                auth,
+                peer,
+                &mut update
+            ),
+            (
+                200,
+                String::from("good 1.2.3.4\nnochg 1.2.3.4\nnohost")
+            )
+        );
+        assert_eq!(
+            handle_request(
+                &config,
+                "/nic/update?hostname=Home.Example.com",
+                auth,
+                peer,
+                &mut update
+            ),
+            (200, String::from("good 5.6.7.8"))
+        );
+        assert_eq!(
+            handle_request(
+                &config,
+                "/nic/update?hostname=home.example.com&myip=2606:4700::1",
+                auth,
+                peer,
+                &mut update
+            ),
+            (200, String::from("good 2606:4700::1"))
+        );
+        for myip in [
+            "127.0.0.1",
+            "10.0.0.2",
+            "100.64.0.1",
+            "fd00::1",
+            "2001:db8::1",
+        ] {
+            assert_eq!(
+                handle_request(
+                    &config,
+                    &format!("/nic/update?hostname=home.example.com&myip={}", myip),
+                    auth,
+                    peer,
+                    &mut update
+                ),
+                (200, String::from("dnserr"))
+            );
+        }
+        // a client on the local network without myip gets the same answer
+        assert_eq!(
+            handle_request(
+                &config,
+                "/nic/update?hostname=home.example.com",
+                auth,
+                "192.168.1.2".parse().unwrap(),
+                &mut update
+            ),
+            (200, String::from("dnserr"))
+        );
+        assert_eq!(
+            handle_request(&config, "/nic/update?myip=1.2.3.4", auth, peer, &mut update),
+            (200, String::from("notfqdn"))
+        );
+        assert_eq!(
+            handle_request(
+                &config,
+                "/nic/update?hostname=home.example.com&myip=localhost",
+                auth,
+                peer,
+                &mut update
+            ),
+            (200, String::from("911"))
+        );
+        assert_eq!(
+            handle_request(
+                &config,
+                "/nic/update?hostname=home.example.com",
+                Some("Basic cm91dGVyOndyb25n"),
+                peer,
+                &mut update
+            ),
+            (401, String::from("badauth"))
+        );
+        assert_eq!(
+            handle_request(
+                &config,
+                "/nic/update?hostname=home.example.com",
+                None,
+                peer,
+                &mut update
+            )
+            .0,
+            401
+        );
+        assert_eq!(
+            handle_request(&config, "/other", auth, peer, &mut update).0,
+            404
+        );
+
+        assert_eq!(
+            updates,
+            vec![
+                (
+                    String::from("home.example.com"),
+                    RRType::A,
+                    String::from("1.2.3.4")
+                ),
+                (
+                    String::from("vpn.example.com"),
+                    RRType::A,
+                    String::from("1.2.3.4")
+                ),
+                (
+                    String::from("home.example.com"),
+                    RRType::A,
+                    String::from("5.6.7.8")
+                ),
+                (
+                    String::from("home.example.com"),
+                    RRType::AAAA,
+                    String::from("2606:4700::1")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_head_limits() -> io::Result<()> {
+        let head = |request: &str| read_head(&mut io::Cursor::new(request.as_bytes()));
+
+        assert_eq!(
+            head("GET /nic/update?hostname=x HTTP/1.1\r\nAuthorization: Basic abc\r\n\r\n")?,
+            Ok((
+                String::from("/nic/update?hostname=x"),
+                Some(String::from("Basic abc"))
+            ))
+        );
+        assert_eq!(head("")?, Err(400));
+        assert_eq!(
+            head(&format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(9000)))?,
+            Err(400)
+        );
+        assert_eq!(
+            head(&format!(
+                "GET / HTTP/1.1\r\n{}\r\n",
+                "X-A: b\r\n".repeat(65)
+            ))?,
+            Err(431)
+        );
+        assert_eq!(
+            head(&format!(
+                "GET / HTTP/1.1\r\nX-A: {}\r\n\r\n",
+                "b".repeat(9000)
+            ))?,
+            Err(431)
+        );
+        assert_eq!(
+            head(&format!(
+                "GET / HTTP/1.1\r\n{}\r\n",
+                format!("X-A: {}\r\n", "b".repeat(8000)).repeat(3)
+            ))?,
+            Err(431)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_request_deadline() -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let mut client = TcpStream::connect(listener.local_addr()?)?;
+        let (stream, _) = listener.accept()?;
+
+        // the client has sent part of its request when the deadline passes
+        client.write_all(b"GET / HTTP/1.1\r\n")?;
+        let mut reader = BufReader::new(DeadlineReader {
+            stream: &stream,
+            deadline: Instant::now(),
+        });
+        let err = read_head(&mut reader).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        Ok(())
+    }
+}
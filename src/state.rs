@@ -0,0 +1,204 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{NsddnsError, RecordTarget, Result};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Last known state of a single record target
+pub struct StateEntry {
+    /// IP the record was last known to hold
+    pub ip: String,
+    /// TTL the record was last known to have, if known
+    pub ttl: Option<u32>,
+    /// Unix time (seconds) at which the record was last updated or confirmed to hold `ip`
+    pub updated_at: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+/// Cache of record state between runs, used to skip provider calls when the IP has not changed
+pub struct State {
+    entries: HashMap<String, StateEntry>,
+    dirty: bool,
+}
+
+/// Current unix time in seconds
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Key identifying a record target in the state file
+fn state_key(target: &RecordTarget) -> String {
+    format!(
+        "{}/{}/{}",
+        target.provider,
+        target.record_type,
+        target.host()
+    )
+}
+
+impl State {
+    /// Load the state file, or an empty state if it does not exist yet
+    pub fn load(path: &Path) -> Result<State> {
+        let state_data = match fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(State::default()),
+            Err(e) => {
+                return Err(NsddnsError::io(
+                    format!("Failed to read {}", path.display()),
+                    e,
+                ))
+            }
+        };
+
+        let state_json = json::parse(&state_data).map_err(|e| {
+            NsddnsError::Parse(format!("{} is not valid JSON: {}", path.display(), e))
+        })?;
+
+        let mut entries = HashMap::new();
+        for (key, entry) in state_json["records"].entries() {
+            let (Some(ip), Some(updated_at)) = (entry["ip"].as_str(), entry["updated_at"].as_u64())
+            else {
+                // a damaged entry only means that record gets checked with the provider again
+                continue;
+            };
+            entries.insert(
+                key.to_owned(),
+                StateEntry {
+                    ip: ip.to_owned(),
+                    // entries written before TTLs were cached have none
+                    ttl: entry["ttl"].as_u32(),
+                    updated_at,
+                },
+            );
+        }
+
+        Ok(State {
+            entries,
+            dirty: false,
+        })
+    }
+
+    /// Write the state file if anything changed since it was loaded
+    pub fn save(&mut self, path: &Path) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let mut records = json::JsonValue::new_object();
+        for (key, entry) in &self.entries {
+            records[key.as_str()] = json::object! {
+                ip: entry.ip.as_str(),
+                ttl: entry.ttl,
+                updated_at: entry.updated_at,
+            };
+        }
+        let state_json = json::object! { records: records };
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| {
+                NsddnsError::io(format!("Failed to create {}", parent.display()), e)
+            })?;
+        }
+
+        // write then rename, so an interrupted run never leaves a truncated state file
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, state_json.pretty(2))
+            .map_err(|e| NsddnsError::io(format!("Failed to write {}", tmp_path.display()), e))?;
+        fs::rename(&tmp_path, path)
+            .map_err(|e| NsddnsError::io(format!("Failed to write {}", path.display()), e))?;
+
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Cached state of a record target
+    pub fn get(&self, target: &RecordTarget) -> Option<&StateEntry> {
+        self.entries.get(&state_key(target))
+    }
+
+    /// Whether the cache shows the record already holds `ip` and is younger than `max_age`
+    ///
+    /// A record with a configured TTL also has to be known to have that TTL.
+    pub fn is_current(&self, target: &RecordTarget, ip: &str, max_age: Duration) -> bool {
+        self.get(target).is_some_and(|entry| {
+            entry.ip == ip
+                && (target.ttl.is_none() || entry.ttl == target.ttl)
+                && now().saturating_sub(entry.updated_at) < max_age.as_secs()
+        })
+    }
+
+    /// Remember that a record target holds `ip` with `ttl` as of now
+    pub fn record(&mut self, target: &RecordTarget, ip: &str, ttl: Option<u32>) {
+        self.entries.insert(
+            state_key(target),
+            StateEntry {
+                ip: ip.to_owned(),
+                ttl,
+                updated_at: now(),
+            },
+        );
+        self.dirty = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RRType;
+
+    fn target() -> RecordTarget {
+        RecordTarget {
+            domain: String::from("example.com"),
+            subdomain: String::from("www"),
+            record_type: RRType::A,
+            provider: String::from("namesilo"),
+            create_missing: false,
+            ttl: None,
+        }
+    }
+
+    #[test]
+    fn test_state_is_current() {
+        let mut state = State::default();
+        let max_age = Duration::from_secs(3600);
+        assert!(!state.is_current(&target(), "1.2.3.4", max_age));
+
+        state.record(&target(), "1.2.3.4", Some(3603));
+        assert!(state.is_current(&target(), "1.2.3.4", max_age));
+        assert!(!state.is_current(&target(), "5.6.7.8", max_age));
+        assert!(!state.is_current(&target(), "1.2.3.4", Duration::ZERO));
+
+        // a configured TTL the record is not known to have needs a check with the provider
+        let with_ttl = RecordTarget {
+            ttl: Some(3600),
+            ..target()
+        };
+        assert!(!state.is_current(&with_ttl, "1.2.3.4", max_age));
+        state.record(&with_ttl, "1.2.3.4", Some(3600));
+        assert!(state.is_current(&with_ttl, "1.2.3.4", max_age));
+    }
+
+    #[test]
+    fn test_state_round_trip() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join(format!("nsddns-state-test-{}", std::process::id()))
+            .join("state.json");
+
+        let mut state = State::default();
+        state.record(&target(), "1.2.3.4", Some(3600));
+        state.save(&path)?;
+
+        let loaded = State::load(&path)?;
+        assert_eq!(loaded.get(&target()), state.get(&target()));
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        Ok(())
+    }
+}
@@ -0,0 +1,463 @@
+use std::{collections::HashMap, fmt, fs, path::Path};
+
+use crate::{DnsProvider, NsResourceRecord, NsddnsError, RRType, Result, DEFAULT_PROVIDER};
+
+/// Placeholders which zone files can use in record values, and the address each stands for
+pub const PLACEHOLDERS: [(&str, RRType); 2] = [
+    ("{{public_ipv4}}", RRType::A),
+    ("{{public_ipv6}}", RRType::AAAA),
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Desired state of a whole zone, as described by a zone file
+pub struct Zone {
+    /// Domain the records live in
+    pub domain: String,
+    /// Name of the provider which hosts the domain
+    pub provider: String,
+    /// Every record the domain should have
+    pub records: Vec<ZoneRecord>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// A record of a zone file, whose value may still contain placeholders
+pub struct ZoneRecord {
+    /// Fully qualified host of the record
+    pub host: String,
+    /// Type of the record
+    pub record_type: RRType,
+    /// Value of the record, possibly with placeholders such as `{{public_ipv4}}`
+    pub value: String,
+    /// TTL of the record, or none to keep whatever the live record has
+    pub ttl: Option<u32>,
+    /// Priority of MX and SRV records
+    pub distance: Option<u32>,
+}
+
+impl Zone {
+    /// Read a zone file
+    pub fn load(path: &Path) -> Result<Zone> {
+        let zone_data = fs::read_to_string(path)
+            .map_err(|e| NsddnsError::io(format!("Failed to read {}", path.display()), e))?;
+        parse_zone_json(&zone_data).map_err(|e| match e {
+            NsddnsError::Config(msg) => NsddnsError::Config(format!("{}: {}", path.display(), msg)),
+            e => e,
+        })
+    }
+
+    /// Address types whose current public IP the zone's values refer to
+    pub fn placeholder_types(&self) -> Vec<RRType> {
+        PLACEHOLDERS
+            .iter()
+            .filter(|(placeholder, _)| self.records.iter().any(|r| r.value.contains(placeholder)))
+            .map(|(_, record_type)| *record_type)
+            .collect()
+    }
+
+    /// The zone's records with placeholders replaced by the given public IPs
+    pub fn render(&self, public_ips: &HashMap<RRType, String>) -> Result<Vec<NsResourceRecord>> {
+        self.records
+            .iter()
+            .map(|record| {
+                let mut value = record.value.clone();
+                for (placeholder, record_type) in PLACEHOLDERS {
+                    if !value.contains(placeholder) {
+                        continue;
+                    }
+                    let ip = public_ips.get(&record_type).ok_or_else(|| {
+                        NsddnsError::IpLookup(format!("no public IP for {}", placeholder))
+                    })?;
+                    value = value.replace(placeholder, ip);
+                }
+                Ok(NsResourceRecord {
+                    ttl: record.ttl,
+                    distance: record.distance,
+                    ..NsResourceRecord::new(&record.host, record.record_type, &value)
+                })
+            })
+            .collect()
+    }
+}
+
+/// Parse the contents of a zone file
+fn parse_zone_json(zone_data: &str) -> Result<Zone> {
+    let zone_json = json::parse(zone_data)
+        .map_err(|e| NsddnsError::Config(format!("zone is not valid JSON: {}", e)))?;
+    let domain = zone_json["domain"]
+        .as_str()
+        .map(|domain| domain.trim_end_matches('.').to_ascii_lowercase())
+        .ok_or_else(|| NsddnsError::Config(String::from("zone missing key: domain")))?;
+    let provider = zone_json["provider"]
+        .as_str()
+        .unwrap_or(DEFAULT_PROVIDER)
+        .to_owned();
+    let default_ttl = parse_number(&zone_json, "ttl", "")?;
+    if !zone_json["records"].is_array() {
+        return Err(NsddnsError::Config(String::from(
+            "zone key records must be a list",
+        )));
+    }
+
+    let mut records = Vec::new();
+    for (i, record_json) in zone_json["records"].members().enumerate() {
+        let prefix = format!("records[{}].", i);
+        let missing =
+            |key: &str| NsddnsError::Config(format!("zone missing key: {}{}", prefix, key));
+
+        let host = record_host(
+            record_json["host"]
+                .as_str()
+                .ok_or_else(|| missing("host"))?,
+            &domain,
+        );
+        let record_type = record_json["type"]
+            .as_str()
+            .ok_or_else(|| missing("type"))?
+            .parse::<RRType>()
+            .map_err(|e| NsddnsError::Config(format!("invalid zone key {}type: {}", prefix, e)))?;
+        let value = record_json["value"]
+            .as_str()
+            .ok_or_else(|| missing("value"))?
+            .to_owned();
+        let distance = parse_number(record_json, "priority", &prefix)?;
+        if record_type.has_distance() != distance.is_some() {
+            return Err(NsddnsError::Config(format!(
+                "zone key {}priority must be set for MX and SRV records, and only for them",
+                prefix
+            )));
+        }
+
+        records.push(ZoneRecord {
+            host,
+            record_type,
+            value,
+            ttl: parse_number(record_json, "ttl", &prefix)?.or(default_ttl),
+            distance,
+        });
+    }
+
+    Ok(Zone {
+        domain,
+        provider,
+        records,
+    })
+}
+
+/// Fully qualified host of a zone file record
+///
+/// Hosts are relative to the domain, blank or "@" for the apex, but fully qualified ones (with or
+/// without the trailing dot) are accepted as well.
+fn record_host(host: &str, domain: &str) -> String {
+    let host = host.trim().trim_end_matches('.').to_ascii_lowercase();
+    match host.as_str() {
+        "" | "@" => domain.to_owned(),
+        _ if host == domain || host.ends_with(&format!(".{}", domain)) => host,
+        _ => format!("{}.{}", host, domain),
+    }
+}
+
+/// Parse an optional non-negative number of a zone file
+fn parse_number(json_value: &json::JsonValue, key: &str, prefix: &str) -> Result<Option<u32>> {
+    let value = &json_value[key];
+    if value.is_null() {
+        return Ok(None);
+    }
+    value.as_u32().map(Some).ok_or_else(|| {
+        NsddnsError::Config(format!(
+            "zone key {}{} must be a non-negative number",
+            prefix, key
+        ))
+    })
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// A change which brings a live record in line with the zone file
+pub enum Change {
+    /// Add a record the zone file has but the provider does not
+    Create(NsResourceRecord),
+    /// Change the value, TTL or priority of a live record
+    Update {
+        /// The record as the provider has it
+        old: NsResourceRecord,
+        /// The record as the zone file wants it, with the live record's ID
+        new: NsResourceRecord,
+    },
+    /// Remove a live record the zone file does not have
+    Delete(NsResourceRecord),
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Create(rr) => write!(f, "+ {}", RecordLine(rr)),
+            Change::Delete(rr) => write!(f, "- {}", RecordLine(rr)),
+            Change::Update { old, new } => {
+                write!(f, "~ {}\n    {}", RecordLine(old), RecordLine(new))
+            }
+        }
+    }
+}
+
+/// A record shown as one line of a plan, in zone file order: host, TTL, type, priority, value
+struct RecordLine<'a>(&'a NsResourceRecord);
+
+impl fmt::Display for RecordLine<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rr = self.0;
+        write!(f, "{}", rr.record_host)?;
+        if let Some(ttl) = rr.ttl {
+            write!(f, " {}", ttl)?;
+        }
+        write!(f, " {}", rr.record_type)?;
+        if let (true, Some(distance)) = (rr.record_type.has_distance(), rr.distance) {
+            write!(f, " {}", distance)?;
+        }
+        write!(f, " {}", rr.record_value)
+    }
+}
+
+/// Work out the changes which turn the live records into the desired ones
+///
+/// Records are matched by host and type. A live record holding a desired value is kept (and
+/// updated if its TTL or priority differs), the remaining ones are updated to the remaining
+/// desired values in order, and whatever is left over is created or deleted. A desired record
+/// without a TTL keeps the live record's.
+pub fn plan(desired: &[NsResourceRecord], live: &[NsResourceRecord]) -> Vec<Change> {
+    let same_name = |a: &NsResourceRecord, b: &NsResourceRecord| {
+        a.record_type == b.record_type && a.record_host.eq_ignore_ascii_case(&b.record_host)
+    };
+
+    // first pair up records whose value already matches, then whatever is left by name alone
+    let mut unmatched_live: Vec<Option<&NsResourceRecord>> = live.iter().map(Some).collect();
+    let mut matches: Vec<Option<&NsResourceRecord>> = vec![None; desired.len()];
+    for exact in [true, false] {
+        for (want, matched) in desired.iter().zip(matches.iter_mut()) {
+            if matched.is_some() {
+                continue;
+            }
+            *matched = unmatched_live
+                .iter_mut()
+                .find(|have| {
+                    have.is_some_and(|have| {
+                        same_name(have, want) && (!exact || have.record_value == want.record_value)
+                    })
+                })
+                .and_then(Option::take);
+        }
+    }
+
+    let mut changes: Vec<Change> = desired
+        .iter()
+        .zip(matches)
+        .filter_map(|(want, have)| match have {
+            Some(have) => update(have, want),
+            None => Some(Change::Create(want.clone())),
+        })
+        .collect();
+    changes.extend(
+        unmatched_live
+            .into_iter()
+            .flatten()
+            .map(|have| Change::Delete(have.clone())),
+    );
+    changes
+}
+
+/// The update of a live record to a desired one, if anything differs
+fn update(have: &NsResourceRecord, want: &NsResourceRecord) -> Option<Change> {
+    let new = NsResourceRecord {
+        record_host: have.record_host.clone(),
+        record_id: have.record_id.clone(),
+        ttl: want.ttl.or(have.ttl),
+        distance: if want.record_type.has_distance() {
+            want.distance
+        } else {
+            have.distance
+        },
+        ..want.clone()
+    };
+    if new == *have {
+        None
+    } else {
+        Some(Change::Update {
+            old: have.clone(),
+            new,
+        })
+    }
+}
+
+/// Make the changes of a plan, stopping at the first which fails
+///
+/// Updates go first and deletions last, so a host is never left without records longer than
+/// necessary. The exception are deletions which a creation depends on: a CNAME cannot share its
+/// host with any other record, so replacing one with the other deletes before creating.
+pub fn apply(provider: &dyn DnsProvider, domain: &str, changes: &[Change]) -> Result<()> {
+    let blocks_create = |old: &NsResourceRecord| {
+        changes.iter().any(|change| match change {
+            Change::Create(new) => {
+                old.record_host.eq_ignore_ascii_case(&new.record_host)
+                    && (old.record_type == RRType::CNAME || new.record_type == RRType::CNAME)
+            }
+            _ => false,
+        })
+    };
+    let order = |change: &&Change| match change {
+        Change::Delete(rr) if blocks_create(rr) => 0,
+        Change::Update { .. } => 1,
+        Change::Create(_) => 2,
+        Change::Delete(_) => 3,
+    };
+    let mut ordered: Vec<&Change> = changes.iter().collect();
+    ordered.sort_by_key(order);
+
+    for change in ordered {
+        match change {
+            Change::Create(rr) => {
+                let record_id = provider.create_record(domain, rr)?;
+                crate::notice!(
+                    { "DOMAIN" => domain, "HOST" => rr.record_host, "RECORD_ID" => record_id },
+                    "Created {}", RecordLine(rr)
+                );
+            }
+            Change::Update { old, new } => {
+                provider.update_record(domain, new, &new.record_value)?;
+                crate::notice!(
+                    { "DOMAIN" => domain, "HOST" => new.record_host, "RECORD_ID" => new.record_id },
+                    "Updated {} to {}", RecordLine(old), RecordLine(new)
+                );
+            }
+            Change::Delete(rr) => {
+                provider.delete_record(domain, rr)?;
+                crate::notice!(
+                    { "DOMAIN" => domain, "HOST" => rr.record_host, "RECORD_ID" => rr.record_id },
+                    "Deleted {}", RecordLine(rr)
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mock::MockNamesilo, HttpClient, NamesiloProvider};
+
+    const ZONE: &str = r#"{
+        "domain": "example.com",
+        "ttl": 3600,
+        "records": [
+            {"host": "@", "type": "A", "value": "{{public_ipv4}}"},
+            {"host": "www", "type": "CNAME", "value": "example.com", "ttl": 7200},
+            {"host": "", "type": "MX", "value": "mx1.example.net", "priority": 10},
+            {"host": "", "type": "MX", "value": "mx2.example.net", "priority": 20},
+            {"host": "", "type": "TXT", "value": "v=spf1 mx -all"}
+        ]
+    }"#;
+
+    #[test]
+    fn test_parse_zone() -> Result<()> {
+        let zone = parse_zone_json(ZONE)?;
+        assert_eq!(zone.domain, "example.com");
+        assert_eq!(zone.provider, DEFAULT_PROVIDER);
+        assert_eq!(zone.records.len(), 5);
+        assert_eq!(zone.records[0].host, "example.com");
+        assert_eq!(zone.records[1].host, "www.example.com");
+        assert_eq!(zone.records[1].ttl, Some(7200));
+        assert_eq!(zone.records[2].ttl, Some(3600));
+        assert_eq!(zone.records[3].distance, Some(20));
+        assert_eq!(zone.placeholder_types(), vec![RRType::A]);
+        assert_eq!(
+            record_host("www.example.com.", "example.com"),
+            "www.example.com"
+        );
+        assert_eq!(
+            record_host("WWW.example.com", "example.com"),
+            "www.example.com"
+        );
+        assert_eq!(record_host("example.com", "example.com"), "example.com");
+        assert_eq!(
+            record_host("www.example", "example.com"),
+            "www.example.example.com"
+        );
+
+        let records = zone.render(&HashMap::from([(RRType::A, String::from("1.2.3.4"))]))?;
+        assert_eq!(records[0].record_value, "1.2.3.4");
+        assert!(zone.render(&HashMap::new()).is_err());
+
+        assert!(matches!(
+            parse_zone_json(r#"{"domain": "example.com", "records": [{"host": "", "type": "MX", "value": "mx"}]}"#),
+            Err(NsddnsError::Config(msg)) if msg.contains("priority")
+        ));
+        assert!(parse_zone_json(
+            r#"{"domain": "example.com", "records": [{"host": "", "type": "NS", "value": "ns"}]}"#
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_and_apply() -> Result<()> {
+        let mock = MockNamesilo::start("zone-key")?;
+        mock.add_record("example.com", "example.com", "A", "5.6.7.8");
+        mock.add_record("example.com", "www.example.com", "CNAME", "example.com");
+        mock.add_record("example.com", "example.com", "MX", "mx1.example.net");
+        mock.add_record("example.com", "old.example.com", "A", "5.6.7.8");
+        let provider = NamesiloProvider::new(
+            &mock.provider_config("zone-key"),
+            &HttpClient::new(&Default::default())?,
+        );
+
+        let zone = parse_zone_json(ZONE)?;
+        let desired = zone.render(&HashMap::from([(RRType::A, String::from("1.2.3.4"))]))?;
+        let changes = plan(&desired, &provider.list_records("example.com")?);
+        let summary: Vec<String> = changes.iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            summary,
+            vec![
+                "~ example.com 3603 A 5.6.7.8\n    example.com 3600 A 1.2.3.4",
+                "~ www.example.com 3603 CNAME example.com\n    www.example.com 7200 CNAME example.com",
+                "~ example.com 3603 MX 0 mx1.example.net\n    example.com 3600 MX 10 mx1.example.net",
+                "+ example.com 3600 MX 20 mx2.example.net",
+                "+ example.com 3600 TXT v=spf1 mx -all",
+                "- old.example.com 3603 A 5.6.7.8",
+            ]
+        );
+
+        apply(&provider, "example.com", &changes)?;
+        assert!(plan(&desired, &provider.list_records("example.com")?).is_empty());
+        assert_eq!(mock.records("example.com").len(), 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_replaces_record_with_cname() -> Result<()> {
+        let mock = MockNamesilo::start("zone-key")?;
+        mock.add_record("example.com", "example.com", "A", "5.6.7.8");
+        mock.add_record("example.com", "ftp.example.com", "A", "5.6.7.8");
+        let provider = NamesiloProvider::new(
+            &mock.provider_config("zone-key"),
+            &HttpClient::new(&Default::default())?,
+        );
+
+        let zone = parse_zone_json(
+            r#"{"domain": "example.com", "records": [
+                {"host": "example.com.", "type": "A", "value": "5.6.7.8"},
+                {"host": "ftp", "type": "CNAME", "value": "example.com"}
+            ]}"#,
+        )?;
+        let changes = plan(
+            &zone.render(&HashMap::new())?,
+            &provider.list_records("example.com")?,
+        );
+        assert_eq!(changes.len(), 2);
+        apply(&provider, "example.com", &changes)?;
+        // the A record has to go before the CNAME can be added
+        assert_eq!(
+            mock.requests(),
+            vec!["dnsListRecords", "dnsDeleteRecord", "dnsAddRecord"]
+        );
+        assert_eq!(mock.records("example.com")[1].record_type, RRType::CNAME);
+        Ok(())
+    }
+}